@@ -0,0 +1,161 @@
+//! Benchmarks of the hot paths of the compressor. The inputs are generated
+//! from fixed seeds so that results are comparable across commits.
+
+#[macro_use]
+extern crate criterion;
+extern crate zopfli;
+
+use criterion::{black_box, BatchSize, Criterion};
+
+use zopfli::bench::{calculate_block_symbol_size, BitwiseWriter, LitLen, Lz77Store, ZopfliLongestMatchCache};
+use zopfli::length_limited_code_lengths;
+
+/// A store of `size` items, about one in three a match, positioned as if they
+/// followed each other in the input.
+fn store_items(size: usize, seed: u32) -> Vec<(LitLen, usize)> {
+    let mut x = seed;
+    let mut pos = 0;
+    (0..size).map(|_| {
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        let litlen = if x % 3 == 1 {
+            LitLen::LengthDist((3 + (x >> 8) % 256) as u16, (1 + (x >> 16) % 32768) as u16)
+        } else {
+            LitLen::Literal((x >> 8) as u16 % 256)
+        };
+        let item = (litlen, pos);
+        pos += litlen.size();
+        item
+    }).collect()
+}
+
+fn store(size: usize, seed: u32) -> Lz77Store {
+    let mut store = Lz77Store::new();
+    for (litlen, pos) in store_items(size, seed) {
+        store.append_store_item(litlen, pos);
+    }
+    store
+}
+
+/// Frequencies falling off roughly like those of real literal/length symbols.
+fn frequencies(count: usize) -> Vec<usize> {
+    (0..count).map(|i| 100_000 / (i + 1) + (i * 7919) % 13).collect()
+}
+
+fn bench_length_limited_code_lengths(c: &mut Criterion) {
+    // The code length code has 19 symbols limited to 7 bits, the literal/length
+    // code 288 symbols limited to 15 bits.
+    let code_length_frequencies = frequencies(19);
+    let ll_frequencies = frequencies(288);
+    c.bench_function("length_limited_code_lengths/maxbits 7", |b| {
+        b.iter(|| length_limited_code_lengths(black_box(&code_length_frequencies), 7))
+    });
+    c.bench_function("length_limited_code_lengths/maxbits 15", |b| {
+        b.iter(|| length_limited_code_lengths(black_box(&ll_frequencies), 15))
+    });
+}
+
+fn bench_calculate_block_symbol_size(c: &mut Criterion) {
+    let lz77 = store(100_000, 1);
+    // The lengths of the fixed tree.
+    let ll_lengths: Vec<u32> = (0..288).map(|i| match i {
+        0..=143 => 8,
+        144..=255 => 9,
+        256..=279 => 7,
+        _ => 8,
+    }).collect();
+    let d_lengths = vec![5; 32];
+    c.bench_function("calculate_block_symbol_size/100000 items", |b| {
+        b.iter(|| calculate_block_symbol_size(&ll_lengths, &d_lengths, black_box(&lz77), 0, lz77.size()))
+    });
+}
+
+fn bench_lit_len_dist(c: &mut Criterion) {
+    let items: Vec<(u16, u16, usize)> = store_items(100_000, 2).into_iter().map(|(litlen, pos)| match litlen {
+        LitLen::Literal(lit) => (lit, 0, pos),
+        LitLen::LengthDist(len, dist) => (len, dist, pos),
+    }).collect();
+    c.bench_function("lit_len_dist/100000 items", |b| {
+        b.iter(|| {
+            let mut store = Lz77Store::new();
+            for &(length, dist, pos) in &items {
+                store.lit_len_dist(length, dist, pos);
+            }
+            store
+        })
+    });
+}
+
+fn bench_append_store(c: &mut Criterion) {
+    let parts = vec![store(50_000, 3), store(50_000, 4)];
+    c.bench_function("append_store/2 x 50000 items", |b| {
+        b.iter(|| {
+            let mut combined = Lz77Store::new();
+            for part in &parts {
+                combined.append_store(black_box(part));
+            }
+            combined
+        })
+    });
+
+    let items: Vec<Vec<(LitLen, usize)>> = vec![store_items(50_000, 3), store_items(50_000, 4)];
+    c.bench_function("append_store_item/2 x 50000 items", |b| {
+        b.iter(|| {
+            let mut combined = Lz77Store::new();
+            for part in &items {
+                for &(litlen, pos) in part {
+                    combined.append_store_item(black_box(litlen), pos);
+                }
+            }
+            combined
+        })
+    });
+}
+
+fn bench_cache_sublen(c: &mut Criterion) {
+    // The distances of the longest match cache's sublen array for a match of
+    // the maximum length, getting further away as the length grows.
+    let sublen: Vec<u16> = (0..259).map(|length| 1 + (length / 20) * 1000).collect();
+    c.bench_function("cache sublen round trip", |b| {
+        b.iter_batched(|| (ZopfliLongestMatchCache::new(1), vec![0; 259]), |(mut cache, mut fetched)| {
+            cache.store_sublen(black_box(&sublen), 0, 258);
+            cache.fetch_sublen(0, 258, &mut fetched);
+            fetched
+        }, BatchSize::SmallInput)
+    });
+}
+
+fn bench_bitwise_writer(c: &mut Criterion) {
+    // Many small independent streams, such as the payloads of a server.
+    let payload = vec![0xa5; 200];
+    c.bench_function("bitwise writer/new per stream", |b| {
+        b.iter(|| {
+            for _ in 0..100 {
+                let mut writer = BitwiseWriter::new(vec![]);
+                writer.add_bytes(black_box(&payload)).unwrap();
+                black_box(writer.into_inner());
+            }
+        })
+    });
+    c.bench_function("bitwise writer/reset per stream", |b| {
+        let mut writer = BitwiseWriter::with_capacity(payload.len());
+        b.iter(|| {
+            for _ in 0..100 {
+                writer.reset();
+                writer.add_bytes(black_box(&payload)).unwrap();
+                black_box(writer.get_mut());
+            }
+        })
+    });
+}
+
+criterion_group!(benches,
+    bench_length_limited_code_lengths,
+    bench_calculate_block_symbol_size,
+    bench_lit_len_dist,
+    bench_append_store,
+    bench_cache_sublen,
+    bench_bitwise_writer,
+);
+criterion_main!(benches);
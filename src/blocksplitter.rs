@@ -178,6 +178,24 @@ pub fn blocksplit_lz77(options: &Options, lz77: &Lz77Store, maxblocks: usize, sp
     }
 }
 
+/// Removes the split points between blocks that are estimated to cost less
+/// merged than apart, counting the tree each block needs. Pairs are tried from
+/// the start, so a merged block can be merged again with the block after it.
+pub fn merge_blocks(lz77: &Lz77Store, splitpoints: &mut Vec<usize>) {
+    let mut i = 0;
+    while i < splitpoints.len() {
+        let start = if i == 0 { 0 } else { splitpoints[i - 1] };
+        let end = splitpoints.get(i + 1).cloned().unwrap_or_else(|| lz77.size());
+        let split = splitpoints[i];
+        let separate = estimate_cost(lz77, start, split) + estimate_cost(lz77, split, end);
+        if estimate_cost(lz77, start, end) < separate {
+            splitpoints.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+}
+
 /// Does blocksplitting on uncompressed data.
 /// The output splitpoints are indices in the uncompressed bytes.
 ///
@@ -222,3 +240,48 @@ pub fn blocksplit(options: &Options, in_data: &[u8], instart: usize, inend: usiz
     }
     debug_assert_eq!(splitpoints.len(), nlz77points);
 }
+
+#[cfg(test)]
+mod test {
+    use util::pseudo_random_bytes;
+    use super::*;
+
+    #[test]
+    fn splits_near_change_of_distribution() {
+        let options = Options::default();
+
+        // Low-entropy text followed by bytes that are close to random.
+        let mut data: Vec<u8> = (0..20000).map(|i| b"abracadabra "[(i * i / 7) % 12]).collect();
+        let boundary = data.len();
+        data.extend(pseudo_random_bytes(20000, 12345));
+
+        let mut splitpoints = vec![];
+        blocksplit(&options, &data, 0, data.len(), 15, &mut splitpoints);
+
+        assert!(!splitpoints.is_empty());
+        assert!(splitpoints.iter().any(|&p| (p as isize - boundary as isize).abs() < 500),
+                "no split point near {}: {:?}", boundary, splitpoints);
+    }
+
+    #[test]
+    fn merges_blocks_with_the_same_distribution() {
+        let options = Options::default();
+        let text = b"Blocks of the same text share one tree when merged. ";
+        let mut data: Vec<u8> = (0..6000).map(|i| text[(i * i / 5) % text.len()]).collect();
+        let boundary = data.len();
+        data.extend(pseudo_random_bytes(6000, 99));
+
+        let mut store = Lz77Store::new();
+        let mut s = ZopfliBlockState::new_without_cache(&options, 0, data.len());
+        store.greedy(&mut s, &data, 0, data.len());
+        let text_end = store.litlens.iter()
+            .scan(0, |pos, litlen| { *pos += litlen.size(); Some(*pos) })
+            .position(|pos| pos >= boundary).unwrap() + 1;
+
+        // Two points inside the text and one where the random bytes start:
+        // only the last is worth its extra tree.
+        let mut splitpoints = vec![text_end / 3, 2 * text_end / 3, text_end];
+        merge_blocks(&store, &mut splitpoints);
+        assert_eq!(splitpoints, [text_end]);
+    }
+}
@@ -1,4 +1,6 @@
 use std::cmp;
+#[cfg(feature = "cache-stats")]
+use std::cell::Cell;
 
 use lz77::LongestMatch;
 use util::{ZOPFLI_CACHE_LENGTH, ZOPFLI_MAX_MATCH, ZOPFLI_MIN_MATCH};
@@ -13,20 +15,81 @@ pub struct ZopfliLongestMatchCache {
     length: Vec<u16>,
     dist: Vec<u16>,
     sublen: Vec<u8>,
+    /* Number of (length, dist) entries of sublen stored per position. */
+    cache_length: usize,
+    #[cfg(feature = "cache-stats")]
+    hits: Cell<usize>,
+    #[cfg(feature = "cache-stats")]
+    misses: Cell<usize>,
+}
+
+/// How often lookups in a `ZopfliLongestMatchCache` could be answered from the
+/// cache. Only counted with the `cache-stats` feature.
+#[cfg(feature = "cache-stats")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
 }
 
 impl ZopfliLongestMatchCache {
     pub fn new(blocksize: usize) -> ZopfliLongestMatchCache {
+        ZopfliLongestMatchCache::with_cache_length(blocksize, ZOPFLI_CACHE_LENGTH)
+    }
+
+    /// Like `new`, but remembers up to `cache_length` (at most 256) sublen
+    /// entries per position instead of `ZOPFLI_CACHE_LENGTH`.
+    pub fn with_cache_length(blocksize: usize, cache_length: usize) -> ZopfliLongestMatchCache {
+        assert!(cache_length > 0 && cache_length <= 256);
         ZopfliLongestMatchCache {
             /* length > 0 and dist 0 is invalid combination, which indicates on purpose
             that this cache value is not filled in yet. */
             length: vec![1; blocksize],
             dist: vec![0; blocksize],
             /* Rather large amount of memory. */
-            sublen: vec![0; ZOPFLI_CACHE_LENGTH * blocksize * 3],
+            sublen: vec![0; cache_length * blocksize * 3],
+            cache_length,
+            #[cfg(feature = "cache-stats")]
+            hits: Cell::new(0),
+            #[cfg(feature = "cache-stats")]
+            misses: Cell::new(0),
+        }
+    }
+
+    /// Empties the cache and resizes it for a block of `blocksize` bytes,
+    /// reusing its memory where possible.
+    pub fn reset(&mut self, blocksize: usize) {
+        self.length.clear();
+        self.length.resize(blocksize, 1);
+        self.dist.clear();
+        self.dist.resize(blocksize, 0);
+        self.sublen.clear();
+        self.sublen.resize(self.cache_length * blocksize * 3, 0);
+        #[cfg(feature = "cache-stats")]
+        {
+            self.hits.set(0);
+            self.misses.set(0);
         }
     }
 
+    /// Returns the number of lookups answered from the cache so far.
+    #[cfg(feature = "cache-stats")]
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.get(),
+            misses: self.misses.get(),
+        }
+    }
+
+    #[cfg(feature = "cache-stats")]
+    fn record_lookup(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.set(counter.get() + 1);
+    }
+
+    #[cfg(not(feature = "cache-stats"))]
+    fn record_lookup(&self, _: bool) {}
+
     fn length_at(&self, pos: usize) -> u16 {
         self.length[pos]
     }
@@ -45,20 +108,22 @@ impl ZopfliLongestMatchCache {
 
     /// Returns the length up to which could be stored in the cache.
     fn max_sublen(&self, pos: usize) -> u32 {
-        let start = ZOPFLI_CACHE_LENGTH * pos * 3;
+        let start = self.cache_length * pos * 3;
         if self.sublen[start + 1] == 0 && self.sublen[start + 2] == 0 {
             return 0;  // No sublen cached.
         }
-        self.sublen[start + ((ZOPFLI_CACHE_LENGTH - 1) * 3)] as u32 + 3
+        self.sublen[start + ((self.cache_length - 1) * 3)] as u32 + 3
     }
 
-    /// Stores sublen array in the cache.
-    fn store_sublen(&mut self, sublen: &[u16], pos: usize, length: usize) {
+    /// Stores sublen array in the cache. `sublen` holds the distance for every
+    /// length up to `length`, so must have more than `length` entries.
+    pub fn store_sublen(&mut self, sublen: &[u16], pos: usize, length: usize) {
         if length < 3 {
             return;
         }
+        assert!(sublen.len() > length, "sublen of {} entries is too short for length {}", sublen.len(), length);
 
-        let start = ZOPFLI_CACHE_LENGTH * pos * 3;
+        let start = self.cache_length * pos * 3;
         let mut i = 3;
         let mut j = 0;
         let mut bestlength = 0;
@@ -69,33 +134,35 @@ impl ZopfliLongestMatchCache {
                 self.sublen[start + (j * 3 + 2)] = (sublen[i] >> 8).wrapping_rem(256) as u8;
                 bestlength = i as u32;
                 j += 1;
-                if j >= ZOPFLI_CACHE_LENGTH {
+                if j >= self.cache_length {
                     break;
                 }
             }
             i += 1;
         }
 
-        if j < ZOPFLI_CACHE_LENGTH {
+        if j < self.cache_length {
             debug_assert_eq!(bestlength, length as u32);
-            self.sublen[start + ((ZOPFLI_CACHE_LENGTH - 1) * 3)] = (bestlength - 3) as u8;
+            self.sublen[start + ((self.cache_length - 1) * 3)] = (bestlength - 3) as u8;
         } else {
             debug_assert!(bestlength <= length as u32);
         }
         debug_assert_eq!(bestlength, self.max_sublen(pos));
     }
 
-    /// Extracts sublen array from the cache.
-    fn fetch_sublen(&self, pos: usize, length: usize, sublen: &mut [u16]) {
+    /// Extracts sublen array from the cache into `sublen`, which is reused
+    /// between calls and must have more than `length` entries.
+    pub fn fetch_sublen(&self, pos: usize, length: usize, sublen: &mut [u16]) {
         if length < 3 {
             return;
         }
+        assert!(sublen.len() > length, "sublen of {} entries is too short for length {}", sublen.len(), length);
 
-        let start = ZOPFLI_CACHE_LENGTH * pos * 3;
+        let start = self.cache_length * pos * 3;
         let maxlength = self.max_sublen(pos) as usize;
         let mut prevlength = 0;
 
-        for j in 0..ZOPFLI_CACHE_LENGTH {
+        for j in 0..self.cache_length {
             let length = self.sublen[start + (j * 3)] as usize + 3;
             let dist = self.sublen[start + (j * 3 + 1)] as u16 + 256 * self.sublen[start + (j * 3 + 2)] as u16;
 
@@ -162,6 +229,7 @@ impl Cache for ZopfliLongestMatchCache {
                 longest_match.distance = distance;
                 longest_match.length = length;
                 longest_match.from_cache = true;
+                self.record_lookup(true);
                 return longest_match;
             }
             /* Can't use much of the cache, since the "sublens" need to be calculated,
@@ -170,6 +238,7 @@ impl Cache for ZopfliLongestMatchCache {
             longest_match.limit = limit;
         }
 
+        self.record_lookup(false);
         longest_match
     }
 
@@ -195,3 +264,57 @@ impl Cache for ZopfliLongestMatchCache {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn longer_cache_stores_more_sublen_entries() {
+        // A different distance for every length from 3 to 100.
+        let mut sublen = vec![0; ZOPFLI_MAX_MATCH + 1];
+        for (i, s) in sublen.iter_mut().enumerate().take(101) {
+            *s = i as u16;
+        }
+
+        let mut cache = ZopfliLongestMatchCache::new(1);
+        cache.store_sublen(&sublen, 0, 100);
+        assert_eq!(cache.max_sublen(0), 3 + ZOPFLI_CACHE_LENGTH as u32 - 1);
+
+        let mut cache = ZopfliLongestMatchCache::with_cache_length(1, 128);
+        cache.store_sublen(&sublen, 0, 100);
+        assert_eq!(cache.max_sublen(0), 100);
+
+        let mut fetched = vec![0; ZOPFLI_MAX_MATCH + 1];
+        cache.fetch_sublen(0, 100, &mut fetched);
+        assert_eq!(&fetched[3..101], &sublen[3..101]);
+    }
+
+    #[test]
+    fn sublen_round_trips_through_a_reused_buffer() {
+        // Distances grow with the length, as they do for real matches.
+        let sublens: Vec<Vec<u16>> = (0..4).map(|pos| {
+            (0..(ZOPFLI_MAX_MATCH + 1)).map(|length| (1 + pos * 100 + length / 40 * 7) as u16).collect()
+        }).collect();
+        let lengths = [ZOPFLI_MAX_MATCH, 3, 57, 200];
+
+        let mut cache = ZopfliLongestMatchCache::new(4);
+        for (pos, (sublen, &length)) in sublens.iter().zip(lengths.iter()).enumerate() {
+            cache.store_sublen(sublen, pos, length);
+        }
+        let mut fetched = vec![0; ZOPFLI_MAX_MATCH + 1];
+        for (pos, (sublen, &length)) in sublens.iter().zip(lengths.iter()).enumerate() {
+            cache.fetch_sublen(pos, length, &mut fetched);
+            assert_eq!(&fetched[3..(length + 1)], &sublen[3..(length + 1)], "position {}", pos);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "too short")]
+    fn fetch_sublen_rejects_short_buffers() {
+        let sublen = vec![1; 11];
+        let mut cache = ZopfliLongestMatchCache::new(1);
+        cache.store_sublen(&sublen, 0, 10);
+        cache.fetch_sublen(0, 10, &mut [0; 10]);
+    }
+}
@@ -0,0 +1,108 @@
+use squeeze::{Scratch, SymbolStats, Workspace};
+use {compress_with_workspace, Format, Options};
+
+/// Keeps the hash, the longest match cache and the other buffers of the
+/// compressor between calls, so that compressing many inputs one after another
+/// doesn't allocate them again for every one. Unless a seed is set, the output
+/// is the same as that of `compress`.
+///
+/// The buffers grow to fit the largest block compressed so far and keep that
+/// size until the context is dropped.
+pub struct ZopfliContext {
+    scratch: Scratch,
+}
+
+impl ZopfliContext {
+    pub fn new() -> ZopfliContext {
+        ZopfliContext {
+            scratch: Scratch::new(),
+        }
+    }
+
+    /// Compresses `in_data` and appends the result to `out`.
+    pub fn compress_into(&mut self, options: &Options, output_type: &Format, in_data: &[u8], out: &mut Vec<u8>) {
+        compress_with_workspace(options, output_type, in_data, out, &mut Workspace { scratch: &mut self.scratch, progress: &mut |_| {} })
+            .expect("writing to a Vec never fails");
+    }
+
+    /// Starts the optimal parse of every block of the following compressions
+    /// from `seed` rather than from the statistics of a greedy parse. Seeded
+    /// with the `stats` of a similar input compressed before, the parse can
+    /// reach the same size in fewer iterations.
+    pub fn set_seed(&mut self, seed: Option<SymbolStats>) {
+        self.scratch.seed = seed;
+    }
+
+    /// The statistics the optimal parse of the last block compressed found its
+    /// best parse with, to seed the compression of similar inputs with.
+    pub fn stats(&self) -> Option<SymbolStats> {
+        self.scratch.stats
+    }
+}
+
+impl Default for ZopfliContext {
+    fn default() -> ZopfliContext {
+        ZopfliContext::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use flate2::read::DeflateDecoder;
+
+    use super::*;
+    use {compress_to_vec, OptionsBuilder};
+
+    #[test]
+    fn matches_one_shot_compress() {
+        let options = OptionsBuilder::new().numiterations(2).build();
+        let inputs: Vec<Vec<u8>> = vec![
+            b"A context reused for many small inputs. ".repeat(10),
+            vec![],
+            (0..300u32).map(|i| (i * i / 7) as u8).collect(),
+            b"Short.".to_vec(),
+            b"A context reused for many small inputs. ".repeat(10),
+        ];
+
+        let mut context = ZopfliContext::new();
+        for output_type in &[Format::Gzip, Format::Zlib, Format::Deflate] {
+            for data in &inputs {
+                let mut out = vec![];
+                context.compress_into(&options, output_type, data, &mut out);
+                assert_eq!(out, compress_to_vec(&options, output_type, data));
+            }
+        }
+    }
+
+    #[test]
+    fn appends_to_output() {
+        let options = OptionsBuilder::new().numiterations(1).build();
+        let mut context = ZopfliContext::new();
+        let mut out = b"prefix".to_vec();
+        context.compress_into(&options, &Format::Deflate, b"data", &mut out);
+        assert!(out.starts_with(b"prefix"));
+        assert_eq!(&out[6..], &compress_to_vec(&options, &Format::Deflate, b"data")[..]);
+    }
+
+    #[test]
+    fn seeds_with_the_stats_of_an_earlier_input() {
+        let options = OptionsBuilder::new().numiterations(2).build();
+        let first = b"The first of many similar records; id=1, name=first. ".repeat(10);
+        let second = b"The second of many similar records; id=2, name=second. ".repeat(10);
+
+        let mut context = ZopfliContext::new();
+        assert!(context.stats().is_none());
+        context.compress_into(&options, &Format::Deflate, &first, &mut vec![]);
+        let stats = context.stats();
+        assert!(stats.is_some());
+
+        context.set_seed(stats);
+        let mut out = vec![];
+        context.compress_into(&options, &Format::Deflate, &second, &mut out);
+        let mut decompressed = vec![];
+        DeflateDecoder::new(&out[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, second);
+    }
+}
@@ -1,16 +1,21 @@
 use std::cmp;
 use std::io::{self, Write};
 
-use blocksplitter::{blocksplit, blocksplit_lz77};
-use katajainen::length_limited_code_lengths;
+use blocksplitter::{blocksplit, blocksplit_lz77, merge_blocks};
+use katajainen::{limited_code_lengths, limited_code_lengths_into};
 use lz77::{ZopfliBlockState, Lz77Store, LitLen};
-use squeeze::{lz77_optimal_fixed, lz77_optimal};
+use squeeze::{lz77_optimal_fixed, lz77_optimal, Workspace};
 use symbols::{get_length_symbol, get_dist_symbol, get_length_symbol_extra_bits, get_dist_symbol_extra_bits, get_length_extra_bits_value, get_length_extra_bits, get_dist_extra_bits_value, get_dist_extra_bits};
-use tree::{lengths_to_symbols};
-use util::{ZOPFLI_NUM_LL, ZOPFLI_NUM_D, ZOPFLI_MASTER_BLOCK_SIZE};
-use Options;
+use tree::{lengths_to_symbols, validate_code_lengths};
+use util::{ZOPFLI_NUM_LL, ZOPFLI_NUM_D};
+use {Options, ProgressEvent};
 use iter::IsFinalIterator;
 
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+#[cfg(feature = "rayon")]
+use squeeze::Scratch;
+
 /// Compresses according to the deflate specification and append the compressed
 /// result to the output.
 ///
@@ -22,17 +27,32 @@ use iter::IsFinalIterator;
 /// `in_data`: the input bytes
 /// `out`: pointer to the dynamic output array to which the result is appended. Must
 ///   be freed after use.
-pub fn deflate<W>(options: &Options, btype: BlockType, in_data: &[u8], out: W) -> io::Result<()>
+///
+/// `work`: the scratch buffers to use and the callback that is called with the
+///   progress of the compression
+pub fn deflate<W>(options: &Options, btype: BlockType, in_data: &[u8], out: W, work: &mut Workspace) -> io::Result<()>
+    where W: Write
+{
+    deflate_from(options, btype, in_data, 0, out, work)
+}
+
+/// Like `deflate`, but only compresses `in_data[instart..]`, with the bytes
+/// before it as the initial dictionary that matches may refer back into.
+pub fn deflate_from<W>(options: &Options, btype: BlockType, in_data: &[u8], instart: usize, out: W, work: &mut Workspace) -> io::Result<()>
     where W: Write
 {
     let mut bitwise_writer = BitwiseWriter::new(out);
-    let mut i = 0;
+    let mut i = instart;
     let insize = in_data.len();
-    while i < insize {
-        let final_block = i + ZOPFLI_MASTER_BLOCK_SIZE >= insize;
-        let size = if final_block { insize - i } else { ZOPFLI_MASTER_BLOCK_SIZE };
-        try!(deflate_part(options, btype, final_block, in_data, i, i + size, &mut bitwise_writer));
+    // Runs at least once, so that empty input still gets a final block.
+    loop {
+        let final_block = i + options.masterblocksize >= insize;
+        let size = if final_block { insize - i } else { options.masterblocksize };
+        try!(deflate_part(options, btype, final_block, in_data, (i, i + size), &mut bitwise_writer, work));
         i += size;
+        if i >= insize {
+            break;
+        }
     }
     bitwise_writer.finish_partial_bits()
 }
@@ -44,10 +64,10 @@ pub fn deflate<W>(options: &Options, btype: BlockType, in_data: &[u8], out: W) -
 /// previous bytes are used as the initial dictionary for LZ77.
 /// This function will usually output multiple deflate blocks. If final is true, then
 /// the final bit will be set on the last block.
-/// Like deflate, but allows to specify start and end byte with instart and
-/// inend. Only that part is compressed, but earlier bytes are still used for the
-/// back window.
-fn deflate_part<W>(options: &Options, btype: BlockType, final_block: bool, in_data: &[u8], instart: usize, inend: usize, bitwise_writer: &mut BitwiseWriter<W>) -> io::Result<()>
+/// Like deflate, but allows to specify start and end byte with the range
+/// `(instart, inend)`. Only that part is compressed, but earlier bytes are still
+/// used for the back window.
+pub fn deflate_part<W>(options: &Options, btype: BlockType, final_block: bool, in_data: &[u8], (instart, inend): (usize, usize), bitwise_writer: &mut BitwiseWriter<W>, work: &mut Workspace) -> io::Result<()>
     where W: Write
 {
     /* If btype=Dynamic is specified, it tries all block types. If a lesser btype is
@@ -55,7 +75,7 @@ fn deflate_part<W>(options: &Options, btype: BlockType, final_block: bool, in_da
     block splitting as they have no dynamic huffman trees. */
     match btype {
         BlockType::Uncompressed => {
-            add_non_compressed_block(final_block, in_data, instart, inend, bitwise_writer)
+            add_non_compressed_block(final_block, &in_data[instart..inend], bitwise_writer)
         },
         BlockType::Fixed => {
             let mut store = Lz77Store::new();
@@ -65,7 +85,7 @@ fn deflate_part<W>(options: &Options, btype: BlockType, final_block: bool, in_da
             add_lz77_block(options, btype, final_block, in_data, &store, 0, store.size(), 0, bitwise_writer)
         },
         BlockType::Dynamic => {
-            blocksplit_attempt(options, final_block, in_data, instart, inend, bitwise_writer)
+            blocksplit_attempt(options, final_block, in_data, instart, inend, bitwise_writer, work)
         },
     }
 }
@@ -77,14 +97,16 @@ pub enum BlockType {
     Dynamic,
 }
 
-fn fixed_tree() -> (Vec<u32>, Vec<u32>) {
-    let mut ll = Vec::with_capacity(ZOPFLI_NUM_LL);
-    ll.resize(144, 8);
-    ll.resize(256, 9);
-    ll.resize(280, 7);
-    ll.resize(288, 8);
-    let d = vec![5; ZOPFLI_NUM_D];
-    (ll, d)
+/// Gets the code lengths of the fixed Huffman tree, as specified in RFC 1951.
+fn fixed_tree() -> ([u32; ZOPFLI_NUM_LL], [u32; ZOPFLI_NUM_D]) {
+    let mut ll = [8; ZOPFLI_NUM_LL];
+    for l in &mut ll[144..256] {
+        *l = 9;
+    }
+    for l in &mut ll[256..280] {
+        *l = 7;
+    }
+    (ll, [5; ZOPFLI_NUM_D])
 }
 
 /// Changes the population counts in a way that the consequent Huffman tree
@@ -198,55 +220,66 @@ fn patch_distance_codes_for_buggy_decoders(d_lengths: &mut[u32]) {
 /// Same as `calculate_block_symbol_size`, but for block size smaller than histogram
 /// size.
 fn calculate_block_symbol_size_small(ll_lengths: &[u32], d_lengths: &[u32], lz77: &Lz77Store, lstart: usize, lend: usize) -> usize {
-    let mut result = 0;
+    let mut result: usize = 0;
 
-    debug_assert!(lend - 1 < lz77.size());
+    debug_assert!(lend <= lz77.size());
 
     for &item in &lz77.litlens[lstart..lend] {
         match item {
             LitLen::Literal(litlens_i) => {
                 debug_assert!(litlens_i < 259);
-                result += ll_lengths[litlens_i as usize]
+                result += ll_lengths[litlens_i as usize] as usize
             },
             LitLen::LengthDist(litlens_i, dists_i) => {
                 debug_assert!(litlens_i < 259);
                 let ll_symbol = get_length_symbol(litlens_i as usize);
                 let d_symbol = get_dist_symbol(dists_i as i32);
-                result += ll_lengths[ll_symbol as usize];
-                result += d_lengths[d_symbol as usize];
-                result += get_length_symbol_extra_bits(ll_symbol) as u32;
-                result += get_dist_symbol_extra_bits(d_symbol) as u32;
+                result += ll_lengths[ll_symbol as usize] as usize;
+                result += d_lengths[d_symbol as usize] as usize;
+                result += get_length_symbol_extra_bits(ll_symbol) as usize;
+                result += get_dist_symbol_extra_bits(d_symbol) as usize;
             },
         }
     }
-    result += ll_lengths[256]; // end symbol
-    result as usize
+    result += ll_lengths[256] as usize; // end symbol
+    result
+}
+
+/// Same as `calculate_block_symbol_size`, but from the histogram of the block
+/// rather than its symbols.
+fn calculate_block_symbol_size_from_counts(ll_counts: &[usize], d_counts: &[usize], ll_lengths: &[u32], d_lengths: &[u32]) -> usize {
+    // Counts of big blocks times their lengths can exceed 32 bits, so this
+    // is summed in usize.
+    let mut result = 0;
+    for i in 0..256 {
+        result += ll_lengths[i] as usize * ll_counts[i];
+    }
+    for i in 257..286 {
+        result += ll_lengths[i] as usize * ll_counts[i];
+        result += get_length_symbol_extra_bits(i as i32) as usize * ll_counts[i];
+    }
+    for i in 0..30 {
+        result += d_lengths[i] as usize * d_counts[i];
+        result += get_dist_symbol_extra_bits(i as i32) as usize * d_counts[i];
+    }
+    result += ll_lengths[256] as usize; // end symbol
+    result
 }
 
 /// Same as `calculate_block_symbol_size`, but with the histogram provided by the caller.
+/// Blocks with fewer symbols than the histogram has entries, which includes the
+/// ranges of one or two symbols that block splitting probes, are summed
+/// directly instead, which is cheaper.
 fn calculate_block_symbol_size_given_counts(ll_counts: &[usize], d_counts: &[usize], ll_lengths: &[u32], d_lengths: &[u32], lz77: &Lz77Store, lstart: usize, lend: usize) -> usize {
     if lstart + ZOPFLI_NUM_LL * 3 > lend {
         calculate_block_symbol_size_small(ll_lengths, d_lengths, lz77, lstart, lend)
     } else {
-        let mut result = 0;
-        for i in 0..256 {
-            result += ll_lengths[i] * ll_counts[i] as u32;
-        }
-        for i in 257..286 {
-            result += ll_lengths[i] * ll_counts[i] as u32;
-            result += (get_length_symbol_extra_bits(i as i32) * ll_counts[i] as i32) as u32;
-        }
-        for i in 0..30 {
-            result += d_lengths[i] * d_counts[i] as u32;
-            result += (get_dist_symbol_extra_bits(i as i32) * d_counts[i] as i32) as u32;
-        }
-        result += ll_lengths[256]; // end symbol
-        result as usize
+        calculate_block_symbol_size_from_counts(ll_counts, d_counts, ll_lengths, d_lengths)
     }
 }
 
 /// Calculates size of the part after the header and tree of an LZ77 block, in bits.
-fn calculate_block_symbol_size(ll_lengths: &[u32], d_lengths: &[u32], lz77: &Lz77Store, lstart: usize, lend: usize) -> usize {
+pub fn calculate_block_symbol_size(ll_lengths: &[u32], d_lengths: &[u32], lz77: &Lz77Store, lstart: usize, lend: usize) -> usize {
     if lstart + ZOPFLI_NUM_LL * 3 > lend {
         calculate_block_symbol_size_small(ll_lengths, d_lengths, lz77, lstart, lend)
     } else {
@@ -255,6 +288,47 @@ fn calculate_block_symbol_size(ll_lengths: &[u32], d_lengths: &[u32], lz77: &Lz7
     }
 }
 
+/// The bits of the part after the header and tree of an LZ77 block, by what
+/// they encode. They add up to `calculate_block_symbol_size`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockSymbolBreakdown {
+    pub literals: usize,
+    pub length_symbols: usize,
+    pub dist_symbols: usize,
+    pub length_extra_bits: usize,
+    pub dist_extra_bits: usize,
+    pub end_symbol: usize,
+}
+
+impl BlockSymbolBreakdown {
+    pub fn total(&self) -> usize {
+        self.literals + self.length_symbols + self.dist_symbols
+            + self.length_extra_bits + self.dist_extra_bits + self.end_symbol
+    }
+}
+
+/// Like `calculate_block_symbol_size`, but keeps the bits of each kind apart.
+pub fn calculate_block_symbol_breakdown(ll_lengths: &[u32], d_lengths: &[u32], lz77: &Lz77Store, lstart: usize, lend: usize) -> BlockSymbolBreakdown {
+    let mut ll_counts = [0; ZOPFLI_NUM_LL];
+    let mut d_counts = [0; ZOPFLI_NUM_D];
+    lz77.histogram_into(lstart, lend, &mut ll_counts, &mut d_counts);
+
+    let mut breakdown = BlockSymbolBreakdown::default();
+    for i in 0..256 {
+        breakdown.literals += ll_lengths[i] as usize * ll_counts[i];
+    }
+    for i in 257..286 {
+        breakdown.length_symbols += ll_lengths[i] as usize * ll_counts[i];
+        breakdown.length_extra_bits += get_length_symbol_extra_bits(i as i32) as usize * ll_counts[i];
+    }
+    for i in 0..30 {
+        breakdown.dist_symbols += d_lengths[i] as usize * d_counts[i];
+        breakdown.dist_extra_bits += get_dist_symbol_extra_bits(i as i32) as usize * d_counts[i];
+    }
+    breakdown.end_symbol = ll_lengths[256] as usize;
+    breakdown
+}
+
 /// Encodes the Huffman tree and returns how many bits its encoding takes; only returns the size
 /// and runs faster.
 fn encode_tree_no_output(ll_lengths: &[u32], d_lengths: &[u32], use_16: bool, use_17: bool, use_18: bool) -> usize {
@@ -360,7 +434,7 @@ fn encode_tree_no_output(ll_lengths: &[u32], d_lengths: &[u32], use_16: bool, us
         i += 1;
     }
 
-    let clcl = length_limited_code_lengths(&clcounts, 7);
+    let clcl = limited_code_lengths(&clcounts, 7);
 
     let mut hclen = 15;
     /* Trim zeros. */
@@ -520,7 +594,7 @@ fn encode_tree<W>(ll_lengths: &[u32], d_lengths: &[u32], use_16: bool, use_17: b
         i += 1;
     }
 
-    let clcl = length_limited_code_lengths(&clcounts, 7);
+    let clcl = limited_code_lengths(&clcounts, 7);
     let clsymbols = lengths_to_symbols(&clcl, 7);
 
     let mut hclen = 15;
@@ -606,7 +680,7 @@ fn add_lz77_block<W>(options: &Options, btype: BlockType, final_block: bool, in_
             lz77.pos[lstart]
         };
         let end = pos + length;
-        return add_non_compressed_block(final_block, in_data, pos, end, bitwise_writer);
+        return add_non_compressed_block(final_block, &in_data[pos..end], bitwise_writer);
     }
 
     try!(bitwise_writer.add_bit(final_block as u8));
@@ -616,7 +690,8 @@ fn add_lz77_block<W>(options: &Options, btype: BlockType, final_block: bool, in_
         BlockType::Fixed => {
             try!(bitwise_writer.add_bit(1));
             try!(bitwise_writer.add_bit(0));
-            fixed_tree()
+            let (ll_lengths, d_lengths) = fixed_tree();
+            (ll_lengths.to_vec(), d_lengths.to_vec())
         },
         BlockType::Dynamic => {
             try!(bitwise_writer.add_bit(0));
@@ -646,6 +721,12 @@ fn add_lz77_block<W>(options: &Options, btype: BlockType, final_block: bool, in_
         let compressed_size = bitwise_writer.bytes_written() - detect_block_size;
         println!("compressed block size: {} ({}k) (unc: {})", compressed_size, compressed_size / 1024, uncompressed_size);
     }
+    if options.verbose_more {
+        let breakdown = calculate_block_symbol_breakdown(&ll_lengths, &d_lengths, lz77, lstart, lend);
+        println!("symbol bits: {} (literals {}, lengths {} + {} extra, dists {} + {} extra, end {})",
+                 breakdown.total(), breakdown.literals, breakdown.length_symbols, breakdown.length_extra_bits,
+                 breakdown.dist_symbols, breakdown.dist_extra_bits, breakdown.end_symbol);
+    }
     Ok(())
 }
 
@@ -654,6 +735,9 @@ fn add_lz77_block<W>(options: &Options, btype: BlockType, final_block: bool, in_
 /// dists: ll77 distances
 /// lstart: start of block
 /// lend: end of block (not inclusive)
+/// The size of a dynamic block includes its encoded tree, so that the block
+/// type choice doesn't favor dynamic trees whose header costs more than they
+/// save on tiny blocks.
 pub fn calculate_block_size(lz77: &Lz77Store, lstart: usize, lend: usize, btype: BlockType) -> f64 {
     match btype {
         BlockType::Uncompressed => {
@@ -666,26 +750,57 @@ pub fn calculate_block_size(lz77: &Lz77Store, lstart: usize, lend: usize, btype:
             (blocks * 5 * 8 + length * 8) as f64
         },
         BlockType::Fixed => {
-            let fixed_tree = fixed_tree();
-            let ll_lengths = fixed_tree.0;
-            let d_lengths = fixed_tree.1;
+            let (ll_lengths, d_lengths) = fixed_tree();
 
             let mut result = 3.0; /* bfinal and btype bits */
             result += calculate_block_symbol_size(&ll_lengths, &d_lengths, lz77, lstart, lend) as f64;
             result
         },
         BlockType::Dynamic => {
-            get_dynamic_lengths(lz77, lstart, lend).0 + 3.0
+            estimate_dynamic_cost(lz77, lstart, lend) + 3.0
         },
     }
 }
 
+/// Returns the size in bits of the tree and data of a dynamic block, as
+/// `get_dynamic_lengths` does, for the block split search that needs only the
+/// size. The histograms and code lengths are kept on the stack instead of
+/// allocated.
+pub fn estimate_dynamic_cost(lz77: &Lz77Store, lstart: usize, lend: usize) -> f64 {
+    let mut ll_counts = [0; ZOPFLI_NUM_LL];
+    let mut d_counts = [0; ZOPFLI_NUM_D];
+    lz77.histogram_into(lstart, lend, &mut ll_counts, &mut d_counts);
+    ll_counts[256] = 1;  /* End symbol. */
+
+    let mut ll_counts2 = ll_counts;
+    let mut d_counts2 = d_counts;
+    optimize_huffman_for_rle(&mut ll_counts2);
+    optimize_huffman_for_rle(&mut d_counts2);
+
+    /* The lengths of the counts themselves and of the counts smoothed for RLE,
+    as try_optimize_huffman_for_rle tries them, both pricing the real counts. */
+    let mut ll_lengths = [0; ZOPFLI_NUM_LL];
+    let mut d_lengths = [0; ZOPFLI_NUM_D];
+    let mut cost = |ll_lengths_counts: &[usize], d_lengths_counts: &[usize]| {
+        limited_code_lengths_into(ll_lengths_counts, 15, &mut ll_lengths);
+        limited_code_lengths_into(d_lengths_counts, 15, &mut d_lengths);
+        patch_distance_codes_for_buggy_decoders(&mut d_lengths);
+        calculate_tree_size(&ll_lengths, &d_lengths)
+            + calculate_block_symbol_size_given_counts(&ll_counts, &d_counts, &ll_lengths, &d_lengths, lz77, lstart, lend)
+    };
+    let plain = cost(&ll_counts, &d_counts);
+    let rle = cost(&ll_counts2, &d_counts2);
+    cmp::min(plain, rle) as f64
+}
+
 /// Tries out `OptimizeHuffmanForRle` for this block, if the result is smaller,
 /// uses it, otherwise keeps the original. Returns size of encoded tree and data in
 /// bits, not including the 3-bit block header.
 fn try_optimize_huffman_for_rle(lz77: &Lz77Store, lstart: usize, lend: usize, ll_counts: &[usize], d_counts: &[usize], ll_lengths: Vec<u32>, d_lengths: Vec<u32>) -> (f64, Vec<u32>, Vec<u32>) {
-    let mut ll_counts2 = ll_counts.to_owned();
-    let mut d_counts2 = d_counts.to_owned();
+    let mut ll_counts2 = [0; ZOPFLI_NUM_LL];
+    let mut d_counts2 = [0; ZOPFLI_NUM_D];
+    ll_counts2.copy_from_slice(ll_counts);
+    d_counts2.copy_from_slice(d_counts);
 
     let treesize = calculate_tree_size(&ll_lengths, &d_lengths);
     let datasize = calculate_block_symbol_size_given_counts(ll_counts, d_counts, &ll_lengths, &d_lengths, lz77, lstart, lend);
@@ -693,8 +808,8 @@ fn try_optimize_huffman_for_rle(lz77: &Lz77Store, lstart: usize, lend: usize, ll
     optimize_huffman_for_rle(&mut ll_counts2);
     optimize_huffman_for_rle(&mut d_counts2);
 
-    let ll_lengths2 = length_limited_code_lengths(&ll_counts2, 15);
-    let mut d_lengths2 = length_limited_code_lengths(&d_counts2, 15);
+    let ll_lengths2 = limited_code_lengths(&ll_counts2, 15);
+    let mut d_lengths2 = limited_code_lengths(&d_counts2, 15);
     patch_distance_codes_for_buggy_decoders(&mut d_lengths2[..]);
 
     let treesize2 = calculate_tree_size(&ll_lengths2, &d_lengths2);
@@ -713,15 +828,81 @@ fn try_optimize_huffman_for_rle(lz77: &Lz77Store, lstart: usize, lend: usize, ll
 /// bit lengths. Returns size of encoded tree and data in bits, not including the
 /// 3-bit block header.
 fn get_dynamic_lengths(lz77: &Lz77Store, lstart: usize, lend: usize) -> (f64, Vec<u32>, Vec<u32>) {
-    let (mut ll_counts, d_counts) = lz77.get_histogram(lstart, lend);
+    let mut ll_counts = [0; ZOPFLI_NUM_LL];
+    let mut d_counts = [0; ZOPFLI_NUM_D];
+    lz77.histogram_into(lstart, lend, &mut ll_counts, &mut d_counts);
     ll_counts[256] = 1;  /* End symbol. */
 
-    let ll_lengths = length_limited_code_lengths(&ll_counts, 15);
-    let mut d_lengths = length_limited_code_lengths(&d_counts, 15);
+    let ll_lengths = limited_code_lengths(&ll_counts, 15);
+    let mut d_lengths = limited_code_lengths(&d_counts, 15);
 
     patch_distance_codes_for_buggy_decoders(&mut d_lengths[..]);
 
-    try_optimize_huffman_for_rle(lz77, lstart, lend, &ll_counts, &d_counts, ll_lengths, d_lengths)
+    let result = try_optimize_huffman_for_rle(lz77, lstart, lend, &ll_counts, &d_counts, ll_lengths, d_lengths);
+    debug_assert_eq!(validate_code_lengths(&result.1, 15), Ok(()));
+    debug_assert_eq!(validate_code_lengths(&result.2, 15), Ok(()));
+    result
+}
+
+/// The code lengths of the Huffman trees of a dynamic block, for inspecting
+/// them or handing them to other deflate tools.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DynamicTree {
+    pub ll_lengths: Vec<u8>,
+    pub d_lengths: Vec<u8>,
+}
+
+impl DynamicTree {
+    /// Serializes the tree as the number of lit/len lengths minus 257 and the
+    /// number of dist lengths minus 1, a byte each like HLIT and HDIST of the
+    /// block header, followed by all lengths packed two to a byte, low nibble
+    /// first. Panics if a count or a length doesn't fit.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        assert!((257..=288).contains(&self.ll_lengths.len()), "there must be 257 to 288 lit/len lengths");
+        assert!((1..=32).contains(&self.d_lengths.len()), "there must be 1 to 32 dist lengths");
+
+        let lengths: Vec<u8> = self.ll_lengths.iter().chain(&self.d_lengths).cloned().collect();
+        assert!(lengths.iter().all(|&length| length <= 15), "code lengths must be at most 15");
+
+        let mut bytes = vec![(self.ll_lengths.len() - 257) as u8, (self.d_lengths.len() - 1) as u8];
+        bytes.extend(lengths.chunks(2).map(|pair| pair[0] | pair.get(1).map_or(0, |&high| high << 4)));
+        bytes
+    }
+
+    /// Reconstructs a tree serialized by `to_bytes`, or returns `None` if
+    /// `bytes` isn't one.
+    pub fn from_bytes(bytes: &[u8]) -> Option<DynamicTree> {
+        if bytes.len() < 2 || bytes[0] > 31 || bytes[1] > 31 {
+            return None;
+        }
+        let num_ll = bytes[0] as usize + 257;
+        let num_d = bytes[1] as usize + 1;
+        let packed = &bytes[2..];
+        let num_lengths = num_ll + num_d;
+        if packed.len() != num_lengths / 2 + num_lengths % 2 {
+            return None;
+        }
+
+        let mut lengths: Vec<u8> = packed.iter().flat_map(|&byte| vec![byte & 15, byte >> 4]).collect();
+        if lengths.len() > num_lengths && lengths.pop() != Some(0) {
+            return None;
+        }
+        let d_lengths = lengths.split_off(num_ll);
+        Some(DynamicTree {
+            ll_lengths: lengths,
+            d_lengths,
+        })
+    }
+}
+
+/// Returns the code lengths `get_dynamic_lengths` chooses for a dynamic block
+/// of the given range of `lz77`.
+pub fn dynamic_tree(lz77: &Lz77Store, lstart: usize, lend: usize) -> DynamicTree {
+    let (_, ll_lengths, d_lengths) = get_dynamic_lengths(lz77, lstart, lend);
+    DynamicTree {
+        ll_lengths: ll_lengths.iter().map(|&length| length as u8).collect(),
+        d_lengths: d_lengths.iter().map(|&length| length as u8).collect(),
+    }
 }
 
 /// Adds all lit/len and dist codes from the lists as huffman symbols. Does not add
@@ -763,6 +944,29 @@ fn add_lz77_data<W>(lz77: &Lz77Store, lstart: usize, lend: usize, expected_data_
 fn add_lz77_block_auto_type<W>(options: &Options, final_block: bool, in_data: &[u8], lz77: &Lz77Store, lstart: usize, lend: usize, expected_data_size: usize, bitwise_writer: &mut BitwiseWriter<W>) -> io::Result<()>
     where W: Write
 {
+    if lstart == lend {
+        /* Smallest empty block is represented by fixed block */
+        try!(bitwise_writer.add_bits(final_block as u32, 1));
+        try!(bitwise_writer.add_bits(1, 2));  /* btype 01 */
+        try!(bitwise_writer.add_bits(0, 7));  /* end symbol has code 0000000 */
+        return Ok(());
+    }
+
+    match auto_block_type(options, in_data, lz77, lstart, lend) {
+        (BlockType::Fixed, _, Some(fixedstore)) => {
+            add_lz77_block(options, BlockType::Fixed, final_block, in_data, &fixedstore, 0, fixedstore.size(), expected_data_size, bitwise_writer)
+        },
+        (btype, _, _) => {
+            add_lz77_block(options, btype, final_block, in_data, lz77, lstart, lend, expected_data_size, bitwise_writer)
+        },
+    }
+}
+
+/// Picks the block type `add_lz77_block_auto_type` writes the non-empty range
+/// `lstart..lend` of `lz77` with. Returns it with its size in bits and, if the
+/// data was parsed again for the fixed tree, the store to write a fixed block
+/// from.
+fn auto_block_type(options: &Options, in_data: &[u8], lz77: &Lz77Store, lstart: usize, lend: usize) -> (BlockType, f64, Option<Lz77Store>) {
     let uncompressedcost = calculate_block_size(lz77, lstart, lend, BlockType::Uncompressed);
     let mut fixedcost = calculate_block_size(lz77, lstart, lend, BlockType::Fixed);
     let dyncost = calculate_block_size(lz77, lstart, lend, BlockType::Dynamic);
@@ -772,34 +976,25 @@ fn add_lz77_block_auto_type<W>(options: &Options, final_block: bool, in_data: &[
     blocks which already are pretty good with fixed huffman tree. */
     let expensivefixed = (lz77.size() < 1000) || fixedcost <= dyncost * 1.1;
 
-    let mut fixedstore = Lz77Store::new();
-    if lstart == lend {
-        /* Smallest empty block is represented by fixed block */
-        try!(bitwise_writer.add_bits(final_block as u32, 1));
-        try!(bitwise_writer.add_bits(1, 2));  /* btype 01 */
-        try!(bitwise_writer.add_bits(0, 7));  /* end symbol has code 0000000 */
-        return Ok(());
-    }
+    let mut fixedstore = None;
     if expensivefixed {
         /* Recalculate the LZ77 with lz77_optimal_fixed */
         let instart = lz77.pos[lstart];
         let inend = instart + lz77.get_byte_range(lstart, lend);
 
+        let mut store = Lz77Store::new();
         let mut s = ZopfliBlockState::new(options, instart, inend);
-        lz77_optimal_fixed(&mut s, in_data, instart, inend, &mut fixedstore);
-        fixedcost = calculate_block_size(&fixedstore, 0, fixedstore.size(), BlockType::Fixed);
+        lz77_optimal_fixed(&mut s, in_data, instart, inend, &mut store);
+        fixedcost = calculate_block_size(&store, 0, store.size(), BlockType::Fixed);
+        fixedstore = Some(store);
     }
 
     if uncompressedcost < fixedcost && uncompressedcost < dyncost {
-        add_lz77_block(options, BlockType::Uncompressed, final_block, in_data, lz77, lstart, lend, expected_data_size, bitwise_writer)
+        (BlockType::Uncompressed, uncompressedcost, None)
     } else if fixedcost < dyncost {
-        if expensivefixed {
-            add_lz77_block(options, BlockType::Fixed, final_block, in_data, &fixedstore, 0, fixedstore.size(), expected_data_size, bitwise_writer)
-        } else {
-            add_lz77_block(options, BlockType::Fixed, final_block, in_data, lz77, lstart, lend, expected_data_size, bitwise_writer)
-        }
+        (BlockType::Fixed, fixedcost, fixedstore)
     } else {
-        add_lz77_block(options, BlockType::Dynamic, final_block, in_data, lz77, lstart, lend, expected_data_size, bitwise_writer)
+        (BlockType::Dynamic, dyncost, None)
     }
 }
 
@@ -820,105 +1015,271 @@ pub fn calculate_block_size_auto_type(lz77: &Lz77Store, lstart: usize, lend: usi
 fn add_all_blocks<W>(splitpoints: &[usize], lz77: &Lz77Store, options: &Options, final_block: bool, in_data: &[u8], bitwise_writer: &mut BitwiseWriter<W>) -> io::Result<()>
     where W: Write
 {
-    let mut last = 0;
-    for &item in splitpoints.iter() {
-        try!(add_lz77_block_auto_type(options, false, in_data, lz77, last, item, 0, bitwise_writer));
-        last = item;
+    // Only the last block of the last part may have the final bit set.
+    let ends = splitpoints.iter().cloned().chain(Some(lz77.size()));
+    let mut start = 0;
+    for (end, is_final) in ends.is_final() {
+        try!(add_lz77_block_auto_type(options, final_block && is_final, in_data, lz77, start, end, 0, bitwise_writer));
+        start = end;
     }
-    add_lz77_block_auto_type(options, final_block, in_data, lz77, last, lz77.size(), 0, bitwise_writer)
+    Ok(())
 }
 
-fn blocksplit_attempt<W>(options: &Options, final_block: bool, in_data: &[u8], instart: usize, inend: usize, bitwise_writer: &mut BitwiseWriter<W>) -> io::Result<()>
+/// Calculates the optimal LZ77 data of one block, using the bytes before
+/// `start` as dictionary.
+fn lz77_optimal_block(options: &Options, in_data: &[u8], (start, end): (usize, usize), work: &mut Workspace) -> Lz77Store {
+    let mut s = match work.scratch.cache.take() {
+        Some(lmc) => ZopfliBlockState::with_cache(options, start, end, lmc),
+        None => ZopfliBlockState::new(options, start, end),
+    };
+    let store = lz77_optimal(&mut s, in_data, start, end, options.numiterations, work.scratch.seed, work);
+    debug_assert_eq!(store.verify(in_data), Ok(()));
+    #[cfg(feature = "cache-stats")]
+    {
+        if options.verbose {
+            let stats = s.cache_stats();
+            println!("longest match cache: {} hits, {} misses", stats.hits, stats.misses);
+        }
+    }
+    work.scratch.cache = Some(s.into_cache());
+    store
+}
+
+/// Calculates the optimal LZ77 data of each of the given byte ranges. The
+/// ranges are independent of each other, so with the `rayon` feature they are
+/// done in parallel, and the progress events of each block are collected and
+/// reported in order once they are all done. In parallel, every worker thread
+/// uses a `Scratch` of its own rather than the one of `work`, with the same
+/// seed, and the statistics of the last block are kept in the one of `work`.
+#[cfg(not(feature = "rayon"))]
+fn lz77_optimal_blocks(options: &Options, in_data: &[u8], ranges: &[(usize, usize)], work: &mut Workspace) -> Vec<Lz77Store> {
+    ranges.iter().map(|&range| lz77_optimal_block(options, in_data, range, work)).collect()
+}
+
+#[cfg(feature = "rayon")]
+fn lz77_optimal_blocks(options: &Options, in_data: &[u8], ranges: &[(usize, usize)], work: &mut Workspace) -> Vec<Lz77Store> {
+    let seed = work.scratch.seed;
+    let results: Vec<_> = ranges.par_iter().map_init(|| Scratch { seed, ..Scratch::new() }, |scratch, &range| {
+        let mut events = vec![];
+        let store = lz77_optimal_block(options, in_data, range, &mut Workspace { scratch, progress: &mut |event| events.push(event) });
+        (store, events, scratch.stats)
+    }).collect();
+    results.into_iter().map(|(store, events, stats)| {
+        for event in events {
+            (work.progress)(event);
+        }
+        if stats.is_some() {
+            work.scratch.stats = stats;
+        }
+        store
+    }).collect()
+}
+
+/// How a master block is written: either as its LZ77 data split into blocks,
+/// or stored as it is.
+enum MasterBlock {
+    Split { lz77: Lz77Store, splitpoints: Vec<usize> },
+    Stored,
+}
+
+fn blocksplit_attempt<W>(options: &Options, final_block: bool, in_data: &[u8], instart: usize, inend: usize, bitwise_writer: &mut BitwiseWriter<W>, work: &mut Workspace) -> io::Result<()>
     where W: Write
 {
+    match split_master_block(options, in_data, instart, inend, work) {
+        MasterBlock::Split { lz77, splitpoints } => {
+            add_all_blocks(&splitpoints, &lz77, options, final_block, in_data, bitwise_writer)
+        },
+        MasterBlock::Stored => {
+            add_non_compressed_block(final_block, &in_data[instart..inend], bitwise_writer)
+        },
+    }
+}
+
+/// Does the block splitting and optimal parse of the master block
+/// `instart..inend`, and picks how to write it.
+fn split_master_block(options: &Options, in_data: &[u8], instart: usize, inend: usize, work: &mut Workspace) -> MasterBlock {
     let mut totalcost = 0.0;
-    let mut lz77 = Lz77Store::new();
 
     /* byte coordinates rather than lz77 index */
     let mut splitpoints_uncompressed = Vec::with_capacity(options.blocksplittingmax as usize);
 
-    blocksplit(options, in_data, instart, inend, options.blocksplittingmax as usize, &mut splitpoints_uncompressed);
+    if options.blocksplitting {
+        blocksplit(options, in_data, instart, inend, options.blocksplittingmax as usize, &mut splitpoints_uncompressed);
+    }
     let npoints = splitpoints_uncompressed.len();
     let mut splitpoints = Vec::with_capacity(npoints);
 
+    let mut ranges = Vec::with_capacity(npoints + 1);
     let mut last = instart;
     for &item in &splitpoints_uncompressed {
-        let mut s = ZopfliBlockState::new(options, last, item);
+        ranges.push((last, item));
+        last = item;
+    }
+    ranges.push((last, inend));
+    (work.progress)(ProgressEvent::BlocksSplit { start: instart, end: inend, blocks: ranges.len() });
+
+    let stores = lz77_optimal_blocks(options, in_data, &ranges, work);
+    let mut lz77 = Lz77Store::with_capacity(stores.iter().map(Lz77Store::size).sum());
+    for ((store, &(start, end)), is_final) in stores.iter().zip(&ranges).is_final() {
+        totalcost += calculate_block_size_auto_type(store, 0, store.size());
 
-        let store = lz77_optimal(&mut s, in_data, last, item, options.numiterations);
-        totalcost += calculate_block_size_auto_type(&store, 0, store.size());
+        debug_assert!(!store.is_empty() || start == end);
+        lz77.append_store(store);
 
-        // ZopfliAppendLZ77Store(&store, &lz77);
-        debug_assert!(store.size() > 0);
-        for (&litlens, &pos) in store.litlens.iter().zip(store.pos.iter()) {
-            lz77.append_store_item(litlens, pos);
+        if !is_final {
+            splitpoints.push(lz77.size());
         }
+    }
+
+    /* Second block splitting attempt */
+    if options.blocksplitting && npoints > 1 {
+        let mut splitpoints2 = Vec::with_capacity(splitpoints_uncompressed.len());
 
-        splitpoints.push(lz77.size());
+        blocksplit_lz77(options, &lz77, options.blocksplittingmax as usize, &mut splitpoints2);
 
-        last = item;
+        let totalcost2 = split_blocks_cost(&lz77, &splitpoints2);
+        if totalcost2 < totalcost {
+            splitpoints = splitpoints2;
+            totalcost = totalcost2;
+        }
     }
 
-    let mut s = ZopfliBlockState::new(options, last, inend);
+    /* Splitting after the optimal parse of the whole master block, kept if it
+    is cheaper than both attempts above. */
+    if options.blocksplitting && options.blocksplittinglast {
+        let whole = lz77_optimal_block(options, in_data, (instart, inend), work);
+        let mut splitpoints3 = Vec::with_capacity(options.blocksplittingmax as usize);
+
+        blocksplit_lz77(options, &whole, options.blocksplittingmax as usize, &mut splitpoints3);
 
-    let store = lz77_optimal(&mut s, in_data, last, inend, options.numiterations);
-    totalcost += calculate_block_size_auto_type(&store, 0, store.size());
+        if split_blocks_cost(&whole, &splitpoints3) < totalcost {
+            lz77 = whole;
+            splitpoints = splitpoints3;
+        }
+    }
 
-    // ZopfliAppendLZ77Store(&store, &lz77);
-    debug_assert!(store.size() > 0);
-    for (&litlens, &pos) in store.litlens.iter().zip(store.pos.iter()) {
-        lz77.append_store_item(litlens, pos);
+    if options.merge_blocks {
+        merge_blocks(&lz77, &mut splitpoints);
     }
 
-    /* Second block splitting attempt */
-    if npoints > 1 {
-        let mut splitpoints2 = Vec::with_capacity(splitpoints_uncompressed.len());
-        let mut totalcost2 = 0.0;
+    /* The estimates are exact for the fixed and dynamic blocks and an upper
+    bound for stored ones, so storing the whole master block whenever that is
+    cheaper guarantees the output never grows beyond the stored size. Empty
+    input is left to the smaller empty fixed block. */
+    let storedcost = calculate_block_size(&lz77, 0, lz77.size(), BlockType::Uncompressed);
+    if instart < inend && storedcost < split_blocks_cost(&lz77, &splitpoints) {
+        return MasterBlock::Stored;
+    }
 
-        blocksplit_lz77(options, &lz77, options.blocksplittingmax as usize, &mut splitpoints2);
+    MasterBlock::Split { lz77, splitpoints }
+}
 
-        let mut last = 0;
-        for &item in &splitpoints2 {
-            totalcost2 += calculate_block_size_auto_type(&lz77, last, item);
-            last = item;
+/// Returns the number of bytes `deflate` with `BlockType::Dynamic` writes for
+/// `in_data`. The blocks are picked as they would be for writing, but their
+/// sizes are added up from their estimates, which are exact for the blocks that
+/// are picked, rather than by writing them.
+pub fn deflate_size(options: &Options, in_data: &[u8], work: &mut Workspace) -> usize {
+    let mut bits = 0;
+    let mut i = 0;
+    let insize = in_data.len();
+    loop {
+        let final_block = i + options.masterblocksize >= insize;
+        let size = if final_block { insize - i } else { options.masterblocksize };
+        bits = match split_master_block(options, in_data, i, i + size, work) {
+            MasterBlock::Split { lz77, splitpoints } => {
+                let ends = splitpoints.iter().cloned().chain(Some(lz77.size()));
+                let mut start = 0;
+                for end in ends {
+                    bits = if start == end {
+                        bits + 10
+                    } else {
+                        match auto_block_type(options, in_data, &lz77, start, end) {
+                            (BlockType::Uncompressed, _, _) => non_compressed_block_end(bits, lz77.get_byte_range(start, end)),
+                            (_, cost, _) => bits + cost as usize,
+                        }
+                    };
+                    start = end;
+                }
+                bits
+            },
+            MasterBlock::Stored => non_compressed_block_end(bits, size),
+        };
+        i += size;
+        if i >= insize {
+            break;
         }
-        totalcost2 += calculate_block_size_auto_type(&lz77, last, lz77.size());
+    }
+    bits / 8 + (if bits % 8 > 0 { 1 } else { 0 })
+}
 
-        if totalcost2 < totalcost {
-            splitpoints = splitpoints2;
+/// The bit position after stored blocks of `length` bytes that start at bit
+/// `bits`, as `add_non_compressed_block` writes them. Unlike the estimate of
+/// `calculate_block_size`, this counts the padding up to the byte boundary.
+fn non_compressed_block_end(mut bits: usize, length: usize) -> usize {
+    let mut remaining = length;
+    loop {
+        let chunk = cmp::min(remaining, 65535);
+        /* 3 header bits, padding up to the byte boundary, LEN and NLEN. */
+        bits += 3;
+        bits += (8 - bits % 8) % 8 + 32 + chunk * 8;
+        remaining -= chunk;
+        if remaining == 0 {
+            return bits;
         }
     }
+}
 
-    add_all_blocks(&splitpoints, &lz77, options, final_block, in_data, bitwise_writer)
+/// The estimated size in bits of `lz77` split into blocks at `splitpoints`,
+/// with the best block type for each.
+fn split_blocks_cost(lz77: &Lz77Store, splitpoints: &[usize]) -> f64 {
+    let mut totalcost = 0.0;
+    let mut last = 0;
+    for &item in splitpoints {
+        totalcost += calculate_block_size_auto_type(lz77, last, item);
+        last = item;
+    }
+    totalcost + calculate_block_size_auto_type(lz77, last, lz77.size())
 }
 
 /// Since an uncompressed block can be max 65535 in size, it actually adds
-/// multible blocks if needed.
-fn add_non_compressed_block<W>(final_block: bool, in_data: &[u8], instart: usize, inend: usize, bitwise_writer: &mut BitwiseWriter<W>) -> io::Result<()>
+/// multible blocks if needed. An empty `in_data` still adds one (empty) block.
+fn add_non_compressed_block<W>(final_block: bool, in_data: &[u8], bitwise_writer: &mut BitwiseWriter<W>) -> io::Result<()>
     where W: Write
 {
-    let in_data = &in_data[instart..inend];
+    if in_data.is_empty() {
+        return add_non_compressed_chunk(final_block, in_data, bitwise_writer);
+    }
 
     for (chunk, is_final) in in_data.chunks(65535).is_final() {
-        let blocksize = chunk.len();
-        let nlen = !blocksize;
+        try!(add_non_compressed_chunk(final_block && is_final, chunk, bitwise_writer));
+    }
 
-        try!(bitwise_writer.add_bit((final_block && is_final) as u8));
-        /* BTYPE 00 */
-        try!(bitwise_writer.add_bit(0));
-        try!(bitwise_writer.add_bit(0));
+    Ok(())
+}
 
-        try!(bitwise_writer.finish_partial_bits());
+/// Adds a single stored block of at most 65535 bytes: the 3 header bits, padding
+/// up to the next byte boundary, LEN, NLEN and the raw bytes.
+fn add_non_compressed_chunk<W>(final_block: bool, chunk: &[u8], bitwise_writer: &mut BitwiseWriter<W>) -> io::Result<()>
+    where W: Write
+{
+    let blocksize = chunk.len();
+    debug_assert!(blocksize <= 65535);
+    let nlen = !blocksize;
 
-        try!(bitwise_writer.add_byte((blocksize % 256) as u8));
-        try!(bitwise_writer.add_byte(((blocksize / 256) % 256) as u8));
-        try!(bitwise_writer.add_byte((nlen % 256) as u8));
-        try!(bitwise_writer.add_byte(((nlen / 256) % 256) as u8));
+    try!(bitwise_writer.add_bit(final_block as u8));
+    /* BTYPE 00 */
+    try!(bitwise_writer.add_bit(0));
+    try!(bitwise_writer.add_bit(0));
 
-        try!(bitwise_writer.add_bytes(chunk));
-    }
+    /* Any bits of input up to the next byte boundary are ignored. */
+    try!(bitwise_writer.finish_partial_bits());
 
-    Ok(())
+    try!(bitwise_writer.add_byte((blocksize % 256) as u8));
+    try!(bitwise_writer.add_byte(((blocksize / 256) % 256) as u8));
+    try!(bitwise_writer.add_byte((nlen % 256) as u8));
+    try!(bitwise_writer.add_byte(((nlen / 256) % 256) as u8));
+
+    bitwise_writer.add_bytes(chunk)
 }
 
 pub struct BitwiseWriter<W> {
@@ -931,7 +1292,7 @@ pub struct BitwiseWriter<W> {
 impl<W> BitwiseWriter<W>
     where W: Write
 {
-    fn new(out: W) -> BitwiseWriter<W> {
+    pub fn new(out: W) -> BitwiseWriter<W> {
         BitwiseWriter {
             bit: 0,
             bp: 0,
@@ -950,7 +1311,7 @@ impl<W> BitwiseWriter<W>
     }
 
     /// For adding a slice of bytes.
-    fn add_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+    pub fn add_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
         self.len += bytes.len();
         self.out.write_all(bytes)
     }
@@ -987,7 +1348,7 @@ impl<W> BitwiseWriter<W>
         Ok(())
     }
 
-    fn finish_partial_bits(&mut self) -> io::Result<()> {
+    pub fn finish_partial_bits(&mut self) -> io::Result<()> {
         if self.bp != 0 {
             let bytes = &[self.bit];
             try!(self.add_bytes(bytes));
@@ -996,6 +1357,34 @@ impl<W> BitwiseWriter<W>
         }
         Ok(())
     }
+
+    /// Returns the underlying writer. Any partial byte must already have been
+    /// flushed with `finish_partial_bits`.
+    pub fn into_inner(self) -> W {
+        debug_assert_eq!(self.bp, 0);
+        self.out
+    }
+
+    /// Gets a mutable reference to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+}
+
+impl BitwiseWriter<Vec<u8>> {
+    /// Creates a writer into a new buffer with room for `capacity` bytes.
+    pub fn with_capacity(capacity: usize) -> BitwiseWriter<Vec<u8>> {
+        BitwiseWriter::new(Vec::with_capacity(capacity))
+    }
+
+    /// Empties the buffer, keeping its allocation, and drops any partial byte
+    /// that wasn't finished, so that the next stream starts from scratch.
+    pub fn reset(&mut self) {
+        self.out.clear();
+        self.bit = 0;
+        self.bp = 0;
+        self.len = 0;
+    }
 }
 
 fn set_counts_to_count(counts: &mut [usize], count: usize, i: usize, stride: usize) {
@@ -1006,6 +1395,12 @@ fn set_counts_to_count(counts: &mut [usize], count: usize, i: usize, stride: usi
 
 #[cfg(test)]
 mod test {
+    use std::io::Read;
+
+    use flate2::read::DeflateDecoder;
+
+    use squeeze::Scratch;
+    use util::pseudo_random_bytes;
     use super::*;
 
     #[test]
@@ -1022,4 +1417,451 @@ mod test {
             vec![0, 1, 2, 100, 100, 100, 100, 100, 8, 9]
         )
     }
+
+    #[test]
+    fn rle_optimization_smooths_similar_counts() {
+        // Trailing zeros are left alone, the similar counts before them become
+        // one run of their rounded average.
+        let mut counts = [10, 11, 10, 9, 10, 12, 0, 0];
+        optimize_huffman_for_rle(&mut counts);
+        assert_eq!(counts, [10, 10, 10, 10, 10, 10, 0, 0]);
+    }
+
+    #[test]
+    fn rle_optimization_keeps_distinct_counts() {
+        let mut counts = [1, 20, 40, 80, 160, 0];
+        optimize_huffman_for_rle(&mut counts);
+        assert_eq!(counts, [1, 20, 40, 80, 160, 0]);
+
+        let mut zeros = [0; 8];
+        optimize_huffman_for_rle(&mut zeros);
+        assert_eq!(zeros, [0; 8]);
+    }
+
+    #[test]
+    fn rle_optimization_does_not_upgrade_zeros() {
+        // A short run of zeros inside similar counts ends the stride rather than
+        // being averaged into it.
+        let mut counts = [6, 5, 6, 5, 0, 0, 0, 5, 6, 5, 6];
+        optimize_huffman_for_rle(&mut counts);
+        assert_eq!(counts, [6, 6, 6, 6, 0, 0, 0, 6, 6, 6, 6]);
+    }
+
+    #[test]
+    fn patch_distance_codes_without_codes() {
+        let mut d_lengths = [0; ZOPFLI_NUM_D];
+        patch_distance_codes_for_buggy_decoders(&mut d_lengths);
+        assert_eq!(&d_lengths[..3], &[1, 1, 0]);
+        assert!(d_lengths[2..].iter().all(|&l| l == 0));
+    }
+
+    #[test]
+    fn patch_distance_codes_with_one_code() {
+        let mut d_lengths = [0; ZOPFLI_NUM_D];
+        d_lengths[7] = 1;
+        patch_distance_codes_for_buggy_decoders(&mut d_lengths);
+        assert_eq!(d_lengths.iter().filter(|&&l| l != 0).count(), 2);
+        assert_eq!(d_lengths[0], 1);
+
+        let mut d_lengths = [0; ZOPFLI_NUM_D];
+        d_lengths[0] = 1;
+        patch_distance_codes_for_buggy_decoders(&mut d_lengths);
+        assert_eq!(&d_lengths[..2], &[1, 1]);
+    }
+
+    #[test]
+    fn patch_distance_codes_with_two_codes() {
+        let mut d_lengths = [0; ZOPFLI_NUM_D];
+        d_lengths[3] = 1;
+        d_lengths[29] = 1;
+        let expected = d_lengths;
+        patch_distance_codes_for_buggy_decoders(&mut d_lengths);
+        assert_eq!(d_lengths, expected);
+
+        // The two unused codes 30 and 31 don't count.
+        let mut d_lengths = [0; ZOPFLI_NUM_D];
+        d_lengths[30] = 1;
+        d_lengths[31] = 1;
+        patch_distance_codes_for_buggy_decoders(&mut d_lengths);
+        assert_eq!(&d_lengths[..2], &[1, 1]);
+    }
+
+    #[test]
+    fn fixed_tree_matches_spec() {
+        let (ll_lengths, d_lengths) = fixed_tree();
+        assert!(ll_lengths[0..144].iter().all(|&l| l == 8));
+        assert!(ll_lengths[144..256].iter().all(|&l| l == 9));
+        assert!(ll_lengths[256..280].iter().all(|&l| l == 7));
+        assert!(ll_lengths[280..288].iter().all(|&l| l == 8));
+        assert!(d_lengths.iter().all(|&l| l == 5));
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of bounds")]
+    fn short_length_table_panics() {
+        let mut store = Lz77Store::new();
+        store.lit_len_dist(b'a' as u16, 0, 0);
+        store.lit_len_dist(200, 0, 1);
+        // A table cut off before symbol 200.
+        calculate_block_symbol_size(&[8; 100], &[5; ZOPFLI_NUM_D], &store, 0, store.size());
+    }
+
+    #[test]
+    fn symbol_breakdown_sums_to_symbol_size() {
+        let data: Vec<u8> = b"Where do the bits of a block go? Where do they go? "
+            .iter().cycle().take(3000).cloned()
+            .chain((0..2000u32).map(|i| (i * i / 11) as u8))
+            .collect();
+        let options = Options::default();
+        let mut s = ZopfliBlockState::new_without_cache(&options, 0, data.len());
+        let mut store = Lz77Store::new();
+        store.greedy(&mut s, &data, 0, data.len());
+
+        let size = store.size();
+        let (fixed_ll, fixed_d) = fixed_tree();
+        for &(lstart, lend) in &[(0, size), (0, 50), (10, size / 2), (size / 2, size)] {
+            let (_, ll_lengths, d_lengths) = get_dynamic_lengths(&store, lstart, lend);
+            for &(ll_lengths, d_lengths) in &[(&ll_lengths[..], &d_lengths[..]), (&fixed_ll[..], &fixed_d[..])] {
+                let breakdown = calculate_block_symbol_breakdown(ll_lengths, d_lengths, &store, lstart, lend);
+                assert_eq!(breakdown.total(), calculate_block_symbol_size(ll_lengths, d_lengths, &store, lstart, lend),
+                           "range {}..{}", lstart, lend);
+                assert!(breakdown.literals > 0 && breakdown.length_symbols > 0 && breakdown.dist_symbols > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn dynamic_cost_estimate_matches_dynamic_lengths() {
+        let data: Vec<u8> = b"estimating the cost of a dynamic block, estimating again. "
+            .iter().cycle().take(6000).cloned()
+            .chain((0..3000u32).map(|i| (i * i / 13) as u8))
+            .collect();
+        let options = Options::default();
+        let mut s = ZopfliBlockState::new_without_cache(&options, 0, data.len());
+        let mut store = Lz77Store::new();
+        store.greedy(&mut s, &data, 0, data.len());
+
+        let size = store.size();
+        // Including empty and one-symbol ranges, and ones without matches.
+        for &(lstart, lend) in &[(0, size), (0, 100), (17, size / 2), (size / 3, size), (0, 0), (5, 6), (0, 20)] {
+            let (cost, ll_lengths, d_lengths) = get_dynamic_lengths(&store, lstart, lend);
+            assert_eq!(estimate_dynamic_cost(&store, lstart, lend), cost, "range {}..{}", lstart, lend);
+            let exact = calculate_tree_size(&ll_lengths, &d_lengths)
+                + calculate_block_symbol_size(&ll_lengths, &d_lengths, &store, lstart, lend);
+            assert_eq!(cost, exact as f64, "range {}..{}", lstart, lend);
+        }
+    }
+
+    #[test]
+    fn tree_cost_keeps_tiny_blocks_fixed() {
+        let data = b"tiny block";
+        let options = Options::default();
+        let mut s = ZopfliBlockState::new_without_cache(&options, 0, data.len());
+        let mut store = Lz77Store::new();
+        store.greedy(&mut s, data, 0, data.len());
+        let size = store.size();
+
+        let bits_of = |btype| {
+            let mut writer = BitwiseWriter::new(vec![]);
+            add_lz77_block(&options, btype, true, data, &store, 0, size, data.len(), &mut writer).unwrap();
+            writer.len * 8 + writer.bp as usize
+        };
+        let fixed_bits = bits_of(BlockType::Fixed);
+        let dynamic_bits = bits_of(BlockType::Dynamic);
+        assert_eq!(calculate_block_size(&store, 0, size, BlockType::Fixed), fixed_bits as f64);
+        assert_eq!(calculate_block_size(&store, 0, size, BlockType::Dynamic), dynamic_bits as f64);
+
+        // The symbols alone are cheaper with the dynamic tree, only its header
+        // makes the fixed block the smaller one.
+        let (fixed_ll, fixed_d) = fixed_tree();
+        let (_, ll_lengths, d_lengths) = get_dynamic_lengths(&store, 0, size);
+        assert!(calculate_block_symbol_size(&ll_lengths, &d_lengths, &store, 0, size)
+                < calculate_block_symbol_size(&fixed_ll, &fixed_d, &store, 0, size));
+        assert!(fixed_bits < dynamic_bits);
+
+        let mut auto = BitwiseWriter::new(vec![]);
+        add_lz77_block_auto_type(&options, true, data, &store, 0, size, data.len(), &mut auto).unwrap();
+        auto.finish_partial_bits().unwrap();
+        let mut fixed = BitwiseWriter::new(vec![]);
+        add_lz77_block(&options, BlockType::Fixed, true, data, &store, 0, size, data.len(), &mut fixed).unwrap();
+        fixed.finish_partial_bits().unwrap();
+        assert_eq!(auto.into_inner(), fixed.into_inner());
+    }
+
+    #[test]
+    fn patched_distance_codes_are_complete() {
+        // No distance codes, and a single one, get patched to two one-bit codes.
+        for &used in &[None, Some(0), Some(1), Some(7)] {
+            let mut d_counts = [0; ZOPFLI_NUM_D];
+            if let Some(symbol) = used {
+                d_counts[symbol] = 10;
+            }
+            let mut d_lengths = limited_code_lengths(&d_counts, 15);
+            patch_distance_codes_for_buggy_decoders(&mut d_lengths);
+            assert_eq!(d_lengths.iter().filter(|&&length| length != 0).count(), 2);
+            assert_eq!(validate_code_lengths(&d_lengths, 15), Ok(()), "symbol {:?}", used);
+        }
+    }
+
+    #[test]
+    fn dynamic_tree_round_trips_through_bytes() {
+        let data: Vec<u8> = b"a tree to hand to another tool, a tree to hand over. "
+            .iter().cycle().take(2000).cloned()
+            .chain((0..500u32).map(|i| (i * i / 7) as u8))
+            .collect();
+        let options = Options::default();
+        let mut s = ZopfliBlockState::new_without_cache(&options, 0, data.len());
+        let mut store = Lz77Store::new();
+        store.greedy(&mut s, &data, 0, data.len());
+
+        let tree = dynamic_tree(&store, 0, store.size());
+        let (_, ll_lengths, d_lengths) = get_dynamic_lengths(&store, 0, store.size());
+        assert!(tree.ll_lengths.iter().zip(&ll_lengths).all(|(&a, &b)| a as u32 == b));
+        assert!(tree.d_lengths.iter().zip(&d_lengths).all(|(&a, &b)| a as u32 == b));
+        assert!(tree.ll_lengths.iter().chain(&tree.d_lengths).all(|&length| length <= 15));
+
+        assert_eq!(DynamicTree::from_bytes(&tree.to_bytes()), Some(tree.clone()));
+        // An odd number of lengths leaves the last high nibble unused.
+        let odd = DynamicTree { ll_lengths: vec![8; 257], d_lengths: vec![5; 30] };
+        assert_eq!(DynamicTree::from_bytes(&odd.to_bytes()), Some(odd));
+
+        let bytes = tree.to_bytes();
+        assert_eq!(DynamicTree::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(DynamicTree::from_bytes(&[32, 0]), None);
+    }
+
+    #[test]
+    fn tiny_ranges_match_histogram_symbol_size() {
+        let data = b"tiny ranges, tiny ranges, tiny ranges.";
+        let options = Options::default();
+        let mut s = ZopfliBlockState::new_without_cache(&options, 0, data.len());
+        let mut store = Lz77Store::new();
+        store.greedy(&mut s, data, 0, data.len());
+        assert!(store.litlens.iter().any(|litlen| matches!(*litlen, LitLen::LengthDist(..))));
+
+        let (fixed_ll, fixed_d) = fixed_tree();
+        let (_, ll_lengths, d_lengths) = get_dynamic_lengths(&store, 0, store.size());
+        for &(ll_lengths, d_lengths) in &[(&ll_lengths[..], &d_lengths[..]), (&fixed_ll[..], &fixed_d[..])] {
+            for lstart in 0..(store.size() - 1) {
+                for size in 0..3 {
+                    let lend = lstart + size;
+                    let (ll_counts, d_counts) = store.get_histogram(lstart, lend);
+                    assert_eq!(calculate_block_symbol_size(ll_lengths, d_lengths, &store, lstart, lend),
+                               calculate_block_symbol_size_from_counts(&ll_counts, &d_counts, ll_lengths, d_lengths),
+                               "range {}..{}", lstart, lend);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn block_symbol_size_does_not_overflow_32_bits() {
+        let (ll_lengths, d_lengths) = fixed_tree();
+        let mut ll_counts = vec![0; ZOPFLI_NUM_LL];
+        let mut d_counts = vec![0; ZOPFLI_NUM_D];
+        ll_counts[b'a' as usize] = 1 << 30;
+        // Length symbol 284 and distance symbol 29 have 5 and 13 extra bits.
+        ll_counts[284] = 1 << 28;
+        d_counts[29] = 1 << 28;
+
+        // The counts are used as given once the block is big enough.
+        let lz77 = Lz77Store::new();
+        let size = calculate_block_symbol_size_given_counts(&ll_counts, &d_counts, &ll_lengths, &d_lengths, &lz77, 0, 3 * ZOPFLI_NUM_LL);
+        let expected = 8 * (1 << 30) + (8 + 5) * (1 << 28) + (5 + 13) * (1 << 28) + 7;
+        assert_eq!(size, expected);
+        assert!(size > u32::MAX as usize);
+    }
+
+    #[test]
+    fn incompressible_data_stays_within_stored_size() {
+        let options = ::OptionsBuilder::new().numiterations(1).blocksplittingmax(0).build();
+        for &len in &[1, 100, 3000] {
+            let data = pseudo_random_bytes(len, 1);
+            let mut out = vec![];
+            deflate(&options, BlockType::Dynamic, &data, &mut out, &mut Workspace { scratch: &mut Scratch::new(), progress: &mut |_| {} }).unwrap();
+            assert!(out.len() <= len + 5, "{} bytes became {}", len, out.len());
+            assert_eq!(deflate_size(&options, &data, &mut Workspace { scratch: &mut Scratch::new(), progress: &mut |_| {} }), out.len());
+
+            let mut decompressed = vec![];
+            DeflateDecoder::new(&out[..]).read_to_end(&mut decompressed).unwrap();
+            assert_eq!(decompressed, data);
+        }
+    }
+
+    #[test]
+    fn non_compressed_block_is_split_into_65535_byte_chunks() {
+        let data = pseudo_random_bytes(65535 * 2 + 1000, 2);
+        let mut out = vec![];
+        {
+            let mut writer = BitwiseWriter::new(&mut out);
+            add_non_compressed_block(true, &data, &mut writer).unwrap();
+            writer.finish_partial_bits().unwrap();
+        }
+        assert_eq!(non_compressed_block_end(0, data.len()), 8 * out.len());
+
+        let mut offset = 0;
+        let mut chunks = vec![];
+        while offset < out.len() {
+            let header = out[offset];
+            let len = out[offset + 1] as usize | (out[offset + 2] as usize) << 8;
+            let nlen = out[offset + 3] as usize | (out[offset + 4] as usize) << 8;
+            assert_eq!(header >> 1, 0, "btype must be 00");
+            assert_eq!(len ^ nlen, 0xFFFF, "NLEN must be the complement of LEN");
+            assert_eq!(&out[offset + 5..offset + 5 + len], &data[chunks.iter().sum::<usize>()..][..len]);
+            chunks.push(len);
+            offset += 5 + len;
+            assert_eq!(header & 1 == 1, offset == out.len(), "only the last chunk is final");
+        }
+        assert_eq!(chunks, vec![65535, 65535, 1000]);
+    }
+
+    #[test]
+    fn only_the_last_split_block_is_final() {
+        let options = Options::default();
+        let text = b"Only the last deflate block may have BFINAL set. ";
+        let mut data = text.repeat(20);
+        data.extend(pseudo_random_bytes(1000, 3));
+        data.extend(text.repeat(20));
+
+        let mut store = Lz77Store::new();
+        {
+            let mut s = ZopfliBlockState::new(&options, 0, data.len());
+            store.greedy(&mut s, &data, 0, data.len());
+        }
+        let splitpoints = [store.size() / 3, store.size() * 2 / 3];
+
+        let mut out = vec![];
+        {
+            let mut writer = BitwiseWriter::new(&mut out);
+            add_all_blocks(&splitpoints, &store, &options, true, &data, &mut writer).unwrap();
+            writer.finish_partial_bits().unwrap();
+        }
+
+        // A decoder stops at the first final block, so an early one would
+        // leave input behind and truncate the output.
+        let mut decoder = DeflateDecoder::new(&out[..]);
+        let mut decompressed = vec![];
+        decoder.read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decoder.total_in(), out.len() as u64);
+        assert!(decompressed == data);
+    }
+
+    #[test]
+    fn reset_writer_starts_a_new_stream() {
+        let mut writer = BitwiseWriter::with_capacity(16);
+        writer.add_bytes(b"first").unwrap();
+        writer.add_bits(0b101, 3).unwrap();
+        let capacity = writer.get_mut().capacity();
+
+        // The partial byte of the first stream must not end up in the second.
+        writer.reset();
+        assert_eq!(writer.bytes_written(), 0);
+        writer.add_bits(0b11, 2).unwrap();
+        writer.finish_partial_bits().unwrap();
+        writer.add_bytes(b"second").unwrap();
+        assert_eq!(writer.bytes_written(), 7);
+        assert_eq!(writer.get_mut().capacity(), capacity);
+        assert_eq!(writer.into_inner(), b"\x03second");
+    }
+
+    #[test]
+    fn empty_input_gets_one_final_block_of_every_type() {
+        let options = Options::default();
+        for &btype in &[BlockType::Uncompressed, BlockType::Fixed, BlockType::Dynamic] {
+            let mut out = vec![];
+            deflate(&options, btype, &[], &mut out, &mut Workspace { scratch: &mut Scratch::new(), progress: &mut |_| {} }).unwrap();
+            assert_eq!(out[0] & 1, 1, "{:?} block isn't final", btype);
+
+            let mut decoder = DeflateDecoder::new(&out[..]);
+            let mut decompressed = vec![];
+            decoder.read_to_end(&mut decompressed).unwrap();
+            assert_eq!(decoder.total_in(), out.len() as u64);
+            assert!(decompressed.is_empty());
+        }
+    }
+
+    /// Returns the BTYPE of the first block in a deflate stream.
+    fn first_block_type(out: &[u8]) -> BlockType {
+        match (out[0] >> 1) & 3 {
+            0 => BlockType::Uncompressed,
+            1 => BlockType::Fixed,
+            2 => BlockType::Dynamic,
+            _ => panic!("reserved block type"),
+        }
+    }
+
+    #[test]
+    fn auto_type_picks_cheapest_block() {
+        let options = Options::default();
+        let random = pseudo_random_bytes(2000, 4);
+        let skewed: Vec<u8> = random.iter().map(|&b| b"aaaaaaaabbbbccd "[(b % 16) as usize]).collect();
+        let inputs: Vec<&[u8]> = vec![
+            &random,
+            b"hello hello hello hello",
+            &skewed,
+        ];
+
+        let mut chosen = vec![];
+        for data in inputs {
+            let mut store = Lz77Store::new();
+            {
+                let mut s = ZopfliBlockState::new(&options, 0, data.len());
+                store.greedy(&mut s, data, 0, data.len());
+            }
+
+            let mut fixedstore = Lz77Store::new();
+            {
+                let mut s = ZopfliBlockState::new(&options, 0, data.len());
+                lz77_optimal_fixed(&mut s, data, 0, data.len(), &mut fixedstore);
+            }
+            let costs = [
+                (BlockType::Uncompressed, calculate_block_size(&store, 0, store.size(), BlockType::Uncompressed)),
+                (BlockType::Fixed, calculate_block_size(&fixedstore, 0, fixedstore.size(), BlockType::Fixed)),
+                (BlockType::Dynamic, calculate_block_size(&store, 0, store.size(), BlockType::Dynamic)),
+            ];
+            let cheapest = costs.iter()
+                .fold(costs[2], |best, &cost| if cost.1 < best.1 { cost } else { best })
+                .0;
+
+            let mut out = vec![];
+            {
+                let mut writer = BitwiseWriter::new(&mut out);
+                add_lz77_block_auto_type(&options, true, data, &store, 0, store.size(), data.len(), &mut writer).unwrap();
+                writer.finish_partial_bits().unwrap();
+            }
+            assert_eq!(first_block_type(&out), cheapest);
+            chosen.push(cheapest);
+        }
+
+        // The inputs were picked so that every block type gets exercised.
+        assert_eq!(chosen, vec![BlockType::Uncompressed, BlockType::Fixed, BlockType::Dynamic]);
+    }
+
+    #[test]
+    fn empty_non_compressed_block() {
+        let mut out = vec![];
+        {
+            let mut writer = BitwiseWriter::new(&mut out);
+            add_non_compressed_block(true, &[], &mut writer).unwrap();
+        }
+        assert_eq!(out, vec![1, 0, 0, 0xFF, 0xFF]);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_blocks_match_sequential() {
+        let options = Options::default();
+        let mut data = pseudo_random_bytes(3000, 5);
+        data.extend((0..6000).map(|i| b"abcabd"[i % 6]));
+        data.extend(pseudo_random_bytes(3000, 6).iter().map(|&b| b % 4));
+        let ranges = [(0, 3000), (3000, 9000), (9000, 12000)];
+
+        let parallel = lz77_optimal_blocks(&options, &data, &ranges, &mut Workspace { scratch: &mut Scratch::new(), progress: &mut |_| {} });
+        let sequential: Vec<_> = ranges.iter().map(|&range| lz77_optimal_block(&options, &data, range, &mut Workspace { scratch: &mut Scratch::new(), progress: &mut |_| {} })).collect();
+        for (p, s) in parallel.iter().zip(&sequential) {
+            assert_eq!(p.litlens, s.litlens);
+            assert_eq!(p.pos, s.pos);
+        }
+    }
 }
@@ -0,0 +1,259 @@
+use std::io::{self, Write};
+use std::mem;
+use adler32::RollingAdler32;
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+use crc;
+
+use deflate::{deflate_part, BitwiseWriter, BlockType};
+use gzip;
+use squeeze::{Scratch, Workspace};
+use util::ZOPFLI_WINDOW_SIZE;
+use zlib::zlib_header;
+use {Format, Options};
+
+enum Checksum {
+    Crc32(crc::Digest<'static, u32>),
+    Adler32(RollingAdler32),
+    None,
+}
+
+/// A compressor that implements `Write`, for inputs that are too large to
+/// hold in memory at once.
+///
+/// Input is buffered until a full master block (see
+/// `OptionsBuilder::masterblocksize`) is available, which is then
+/// compressed the same way `compress` would, using the preceding
+/// `ZOPFLI_WINDOW_SIZE` bytes as the initial dictionary for the hash and the
+/// longest match cache. The output is identical to that of `compress` on the
+/// whole input. Only that window and the input not compressed yet are kept in
+/// memory, so memory use doesn't grow with the size of the input. `finish`
+/// must be called to write the final block and the container trailer and to
+/// see any error doing so. An encoder dropped without it does the same on a
+/// best-effort basis, ignoring errors.
+pub struct ZopfliEncoder<W: Write> {
+    options: Options,
+    output_type: Format,
+    /* Only None once finish has taken it. */
+    bitwise_writer: Option<BitwiseWriter<W>>,
+    /* The retained window followed by the input that is not compressed yet. */
+    buffer: Vec<u8>,
+    /* Start of the input in buffer that is not compressed yet. */
+    pos: usize,
+    header_written: bool,
+    checksum: Checksum,
+    insize: u64,
+    /* Reused for every master block. */
+    scratch: Scratch,
+}
+
+impl<W: Write> ZopfliEncoder<W> {
+    pub fn new(options: Options, output_type: Format, out: W) -> ZopfliEncoder<W> {
+        let checksum = match output_type {
+            Format::Gzip => Checksum::Crc32(gzip::CRC_IEEE.digest()),
+            Format::Zlib => Checksum::Adler32(RollingAdler32::new()),
+            Format::Deflate => Checksum::None,
+        };
+        ZopfliEncoder {
+            options,
+            output_type,
+            bitwise_writer: Some(BitwiseWriter::new(out)),
+            buffer: vec![],
+            pos: 0,
+            header_written: false,
+            checksum,
+            insize: 0,
+            scratch: Scratch::new(),
+        }
+    }
+
+    fn bitwise_writer(&mut self) -> &mut BitwiseWriter<W> {
+        self.bitwise_writer.as_mut().expect("the encoder is not finished")
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if self.header_written {
+            return Ok(());
+        }
+        self.header_written = true;
+        match self.output_type {
+            Format::Gzip => self.bitwise_writer().add_bytes(gzip::HEADER),
+            Format::Zlib => {
+                let mut header = [0; 2];
+                (&mut header[..]).write_u16::<BigEndian>(zlib_header())?;
+                self.bitwise_writer().add_bytes(&header)
+            },
+            Format::Deflate => Ok(()),
+        }
+    }
+
+    /// Compresses the pending input in buffer up to `end` as one master block.
+    fn compress_block(&mut self, end: usize, final_block: bool) -> io::Result<()> {
+        self.write_header()?;
+        let bitwise_writer = self.bitwise_writer.as_mut().expect("the encoder is not finished");
+        deflate_part(&self.options, BlockType::Dynamic, final_block, &self.buffer, (self.pos, end), bitwise_writer, &mut Workspace { scratch: &mut self.scratch, progress: &mut |_| {} })?;
+        self.pos = end;
+
+        // Only the window before the next block can still be referenced.
+        let evict = self.pos.saturating_sub(ZOPFLI_WINDOW_SIZE);
+        self.buffer.drain(..evict);
+        self.pos -= evict;
+        Ok(())
+    }
+
+    /// Compresses the remaining input as the final block, writes the container
+    /// trailer and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let result = self.write_final_block();
+        // Taken even on error, so that `drop` doesn't try again.
+        let bitwise_writer = self.bitwise_writer.take().expect("the encoder is not finished");
+        result.map(|()| bitwise_writer.into_inner())
+    }
+
+    /// Does the work of `finish` without giving up the writer, so that `drop`
+    /// can do it too.
+    fn write_final_block(&mut self) -> io::Result<()> {
+        let end = self.buffer.len();
+        self.compress_block(end, true)?;
+        self.bitwise_writer().finish_partial_bits()?;
+        let insize = self.insize;
+        let checksum = mem::replace(&mut self.checksum, Checksum::None);
+        let out = self.bitwise_writer().get_mut();
+        match checksum {
+            Checksum::Crc32(digest) => {
+                out.write_u32::<LittleEndian>(digest.finalize())?;
+                out.write_u32::<LittleEndian>(insize as u32)?;
+            },
+            Checksum::Adler32(adler) => {
+                out.write_u32::<BigEndian>(adler.hash())?;
+            },
+            Checksum::None => {},
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for ZopfliEncoder<W> {
+    fn drop(&mut self) {
+        if self.bitwise_writer.is_some() {
+            let _ = self.write_final_block();
+        }
+    }
+}
+
+impl<W: Write> Write for ZopfliEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.checksum {
+            Checksum::Crc32(ref mut digest) => digest.update(buf),
+            Checksum::Adler32(ref mut adler) => adler.update_buffer(buf),
+            Checksum::None => {},
+        }
+        self.insize += buf.len() as u64;
+        self.buffer.extend_from_slice(buf);
+
+        // A block is only compressed once more input follows it, because the
+        // last one has to be marked final.
+        while self.buffer.len() - self.pos > self.options.masterblocksize {
+            let end = self.pos + self.options.masterblocksize;
+            self.compress_block(end, false)?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Flushes the underlying writer. Buffered input is only compressed once a
+    /// full block is available or on `finish`.
+    fn flush(&mut self) -> io::Result<()> {
+        self.bitwise_writer().get_mut().flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp;
+    use std::io::{Read, Write};
+
+    use flate2::read::DeflateDecoder;
+
+    use super::*;
+    use compress_to_vec;
+    use util::pseudo_random_bytes;
+
+    fn encode(options: &Options, output_type: Format, data: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut encoder = ZopfliEncoder::new(options.clone(), output_type, vec![]);
+        for chunk in data.chunks(chunk_size) {
+            encoder.write_all(chunk).unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn small_writes_match_one_shot_compress() {
+        let options = Options::default();
+        let data: Vec<u8> = b"streaming zopfli, one small write at a time. "
+            .iter().cycle().take(5000).cloned().collect();
+        for &output_type in &[Format::Gzip, Format::Zlib, Format::Deflate] {
+            let expected = compress_to_vec(&options, &output_type, &data);
+            assert_eq!(encode(&options, output_type, &data, 7), expected);
+        }
+    }
+
+    #[test]
+    fn spans_several_master_blocks() {
+        let options = ::OptionsBuilder::new().numiterations(1).masterblocksize(40000).build();
+        // Random bytes have few matches, which keeps this fast.
+        let data = pseudo_random_bytes(2 * 40000 + 1000, 1);
+        let expected = compress_to_vec(&options, &Format::Gzip, &data);
+        assert_eq!(encode(&options, Format::Gzip, &data, 4096), expected);
+    }
+
+    #[test]
+    fn dropping_finishes_the_stream() {
+        let options = ::OptionsBuilder::new().numiterations(1).build();
+        let data = b"Dropped without calling finish. ".repeat(20);
+        for &output_type in &[Format::Gzip, Format::Zlib, Format::Deflate] {
+            let mut out = vec![];
+            {
+                let mut encoder = ZopfliEncoder::new(options.clone(), output_type, &mut out);
+                encoder.write_all(&data).unwrap();
+            }
+            assert_eq!(out, compress_to_vec(&options, &output_type, &data));
+        }
+    }
+
+    #[test]
+    fn empty_input() {
+        let options = Options::default();
+        for &output_type in &[Format::Gzip, Format::Zlib, Format::Deflate] {
+            let expected = compress_to_vec(&options, &output_type, &[]);
+            assert_eq!(encode(&options, output_type, &[], 1), expected);
+        }
+    }
+
+    #[test]
+    fn memory_stays_bounded_while_streaming() {
+        let masterblocksize = 1 << 16;
+        let options = ::OptionsBuilder::new().numiterations(1).blocksplitting(false).masterblocksize(masterblocksize).build();
+        let mut encoder = ZopfliEncoder::new(options, Format::Deflate, vec![]);
+        // Long runs, which are fast to compress, set apart by a few distinct
+        // bytes, written in chunks of varying size.
+        let data: Vec<u8> = (0..3_000_000).map(|i| b"zopfli".get(i % 50000).cloned().unwrap_or(0)).collect();
+        let mut peak = 0;
+        let mut start = 0;
+        for i in 0.. {
+            let end = cmp::min(start + 1000 + (i * 37) % 3000, data.len());
+            encoder.write_all(&data[start..end]).unwrap();
+            peak = cmp::max(peak, encoder.buffer.capacity());
+            start = end;
+            if start == data.len() {
+                break;
+            }
+        }
+        // Room for the window, a master block and a chunk, doubled for the
+        // growth of the buffer.
+        assert!(peak <= 2 * (ZOPFLI_WINDOW_SIZE + masterblocksize + 4000), "peak buffer of {} bytes", peak);
+
+        let compressed = encoder.finish().unwrap();
+        let mut decompressed = vec![];
+        DeflateDecoder::new(&compressed[..]).read_to_end(&mut decompressed).unwrap();
+        assert!(decompressed == data);
+    }
+}
@@ -1,12 +1,16 @@
 use std::io::{self, Write};
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 
 use deflate::{deflate, BlockType};
+use squeeze::Workspace;
 use Options;
 
-const CRC_IEEE: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+pub static CRC_IEEE: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
 
-static HEADER: &'static [u8] = &[
+pub static HEADER: &[u8] = &[
     31,  // ID1
     139, // ID2
     8,   // CM
@@ -21,14 +25,268 @@ static HEADER: &'static [u8] = &[
     3,   // OS follows Unix conventions.
 ];
 
+/// Calculates the CRC-32 checksum of the data, as used in the gzip trailer.
+pub fn crc32(data: &[u8]) -> u32 {
+    CRC_IEEE.checksum(data)
+}
+
+/// Size of the chunks that `crc32_parallel` checksums on separate threads.
+/// Smaller inputs aren't worth splitting.
+#[cfg(feature = "rayon")]
+const CRC32_PARALLEL_CHUNK_SIZE: usize = 1 << 20;
+
+/// Like `crc32`, but checksums chunks of large inputs in parallel and combines
+/// the results with `crc32_combine`.
+#[cfg(feature = "rayon")]
+pub fn crc32_parallel(data: &[u8]) -> u32 {
+    if data.len() <= CRC32_PARALLEL_CHUNK_SIZE {
+        return crc32(data);
+    }
+    /* The checksum of no data is 0, which combines to the other checksum. */
+    data.par_chunks(CRC32_PARALLEL_CHUNK_SIZE)
+        .map(|chunk| (crc32(chunk), chunk.len() as u64))
+        .reduce(|| (0, 0), |(crc1, len1), (crc2, len2)| (crc32_combine(crc1, crc2, len2), len1 + len2))
+        .0
+}
+
+#[cfg(not(feature = "rayon"))]
+fn input_crc32(data: &[u8]) -> u32 {
+    crc32(data)
+}
+
+#[cfg(feature = "rayon")]
+fn input_crc32(data: &[u8]) -> u32 {
+    crc32_parallel(data)
+}
+
+/// The ISIZE field of the gzip trailer: the input size modulo 2^32.
+fn input_size(len: usize) -> u32 {
+    (len as u64 & 0xFFFF_FFFF) as u32
+}
+
 /// Compresses the data according to the gzip specification, RFC 1952.
-pub fn gzip_compress<W>(options: &Options, in_data: &[u8], mut out: W) -> io::Result<()>
+pub fn gzip_compress<W>(options: &Options, in_data: &[u8], mut out: W, work: &mut Workspace) -> io::Result<()>
     where W: Write
 {
     try!(out.by_ref().write_all(HEADER));
 
-    try!(deflate(options, BlockType::Dynamic, in_data, out.by_ref()));
+    try!(deflate(options, BlockType::Dynamic, in_data, out.by_ref(), work));
+
+    try!(out.by_ref().write_u32::<LittleEndian>(input_crc32(in_data)));
+    out.write_u32::<LittleEndian>(input_size(in_data.len()))
+}
+
+/// The CRC-32 polynomial in reversed bit order, for `crc32_combine`.
+const CRC32_POLY_REVERSED: u32 = 0xEDB8_8320;
+
+/// Multiplies the 32x32 bit matrix `mat` over GF(2) with the vector `vec`.
+fn gf2_matrix_times(mat: &[u32; 32], mut vec: u32) -> u32 {
+    let mut sum = 0;
+    for row in mat.iter() {
+        if vec == 0 {
+            break;
+        }
+        if vec & 1 != 0 {
+            sum ^= *row;
+        }
+        vec >>= 1;
+    }
+    sum
+}
+
+fn gf2_matrix_square(square: &mut [u32; 32], mat: &[u32; 32]) {
+    for (row, &mat_row) in square.iter_mut().zip(mat.iter()) {
+        *row = gf2_matrix_times(mat, mat_row);
+    }
+}
+
+/// Combines the CRC-32 `crc1` of some data with the CRC-32 `crc2` of `len2`
+/// bytes following it into the CRC-32 of both, without access to the data.
+/// Ported from zlib's `crc32_combine`: the effect of appending `len2` zero
+/// bytes to the first part is applied with a matrix that is squared for every
+/// bit of `len2`.
+pub fn crc32_combine(mut crc1: u32, crc2: u32, mut len2: u64) -> u32 {
+    if len2 == 0 {
+        return crc1;
+    }
+
+    let mut even = [0u32; 32];
+    /* The operator for one zero bit. */
+    let mut odd = [0u32; 32];
+    odd[0] = CRC32_POLY_REVERSED;
+    for (n, row) in odd.iter_mut().enumerate().skip(1) {
+        *row = 1 << (n - 1);
+    }
+    /* Two and then four zero bits. */
+    gf2_matrix_square(&mut even, &odd);
+    gf2_matrix_square(&mut odd, &even);
+
+    /* Apply len2 zero bytes to crc1, the first square gives the operator for
+    one zero byte, eight zero bits. */
+    loop {
+        gf2_matrix_square(&mut even, &odd);
+        if len2 & 1 != 0 {
+            crc1 = gf2_matrix_times(&even, crc1);
+        }
+        len2 >>= 1;
+        if len2 == 0 {
+            break;
+        }
+
+        gf2_matrix_square(&mut odd, &even);
+        if len2 & 1 != 0 {
+            crc1 = gf2_matrix_times(&odd, crc1);
+        }
+        len2 >>= 1;
+        if len2 == 0 {
+            break;
+        }
+    }
+
+    crc1 ^ crc2
+}
+
+/// Checks that `member` looks like a single gzip member: the magic bytes, the
+/// deflate compression method, no reserved flags, and room for the trailer.
+fn check_member(member: &[u8]) -> io::Result<()> {
+    if member.len() < HEADER.len() + 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "gzip member is too short"));
+    }
+    if member[0] != 31 || member[1] != 139 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "gzip member has the wrong magic bytes"));
+    }
+    if member[2] != 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "gzip member is not compressed with deflate"));
+    }
+    if member[3] & 0xE0 != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "gzip member has reserved flags set"));
+    }
+    Ok(())
+}
+
+/// Joins independently compressed gzip members into one gzip file, which
+/// decompresses to the concatenation of their contents (RFC 1952, section
+/// 2.2). Returns an `InvalidData` error if any of them isn't a gzip member.
+pub fn gzip_concat(members: &[Vec<u8>]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(members.iter().map(Vec::len).sum());
+    for member in members {
+        check_member(member)?;
+        out.extend_from_slice(member);
+    }
+    Ok(out)
+}
+
+/// Returns the CRC-32 and ISIZE of the concatenated contents of `members`,
+/// computed from their trailers with `crc32_combine`. The ISIZE of a member is
+/// its size modulo 2^32, so the CRC is only right for members under 4 GiB.
+pub fn gzip_concat_trailer(members: &[Vec<u8>]) -> io::Result<(u32, u32)> {
+    let mut crc = 0;
+    let mut size = 0u32;
+    for member in members {
+        check_member(member)?;
+        let trailer = &member[(member.len() - 8)..];
+        let member_crc = LittleEndian::read_u32(&trailer[..4]);
+        let member_size = LittleEndian::read_u32(&trailer[4..]);
+        crc = crc32_combine(crc, member_crc, member_size as u64);
+        size = size.wrapping_add(member_size);
+    }
+    Ok((crc, size))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use flate2::read::{GzDecoder, MultiGzDecoder};
+
+    use squeeze::Scratch;
+    #[cfg(feature = "rayon")]
+    use util::pseudo_random_bytes;
+    use super::*;
+
+    fn gunzip(compressed: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        GzDecoder::new(compressed).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn crc32_known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn input_size_wraps_around() {
+        assert_eq!(input_size(1234), 1234);
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(input_size(((1u64 << 32) + 5) as usize), 5);
+        }
+    }
+
+    #[test]
+    fn gunzip_recovers_input() {
+        let data = b"gzip gzip gzip, crc crc crc";
+        let mut compressed = vec![];
+        gzip_compress(&Options::default(), data, &mut compressed, &mut Workspace { scratch: &mut Scratch::new(), progress: &mut |_| {} }).unwrap();
+        assert_eq!(gunzip(&compressed), data.to_vec());
+    }
+
+    #[test]
+    fn empty_input() {
+        let mut compressed = vec![];
+        gzip_compress(&Options::default(), &[], &mut compressed, &mut Workspace { scratch: &mut Scratch::new(), progress: &mut |_| {} }).unwrap();
+        assert_eq!(gunzip(&compressed), Vec::<u8>::new());
+        assert_eq!(&compressed[compressed.len() - 8..], &[0; 8]);
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut compressed = vec![];
+        gzip_compress(&Options::default(), data, &mut compressed, &mut Workspace { scratch: &mut Scratch::new(), progress: &mut |_| {} }).unwrap();
+        compressed
+    }
+
+    #[test]
+    fn crc32_combine_matches_crc_of_concatenation() {
+        let data = b"The checksum of both parts, from the checksums of each part.";
+        for split in 0..(data.len() + 1) {
+            let (first, second) = data.split_at(split);
+            assert_eq!(crc32_combine(crc32(first), crc32(second), second.len() as u64), crc32(data));
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_crc32_matches_serial() {
+        // Several chunks and a partial one at the end.
+        let data = pseudo_random_bytes(5 * CRC32_PARALLEL_CHUNK_SIZE + 12345, 1);
+        assert_eq!(crc32_parallel(&data), crc32(&data));
+        assert_eq!(crc32_parallel(&data[..1000]), crc32(&data[..1000]));
+        assert_eq!(crc32_parallel(&[]), 0);
+    }
+
+    #[test]
+    fn concatenated_members_gunzip_to_concatenated_inputs() {
+        let first = b"The first member, compressed on its own. ".repeat(10);
+        let second = b"And a second one, compressed on its own as well.".repeat(5);
+        let members = vec![gzip(&first), gzip(&second)];
+        let joined = gzip_concat(&members).unwrap();
+
+        let mut out = vec![];
+        MultiGzDecoder::new(&joined[..]).read_to_end(&mut out).unwrap();
+        let mut expected = first.clone();
+        expected.extend_from_slice(&second);
+        assert_eq!(out, expected);
+
+        assert_eq!(gzip_concat_trailer(&members).unwrap(), (crc32(&expected), expected.len() as u32));
+    }
 
-    try!(out.by_ref().write_u32::<LittleEndian>(CRC_IEEE.checksum(in_data)));
-    out.write_u32::<LittleEndian>(in_data.len() as u32)
+    #[test]
+    fn concat_rejects_invalid_members() {
+        let mut not_gzip = gzip(b"data");
+        not_gzip[0] = 0;
+        for members in &[vec![gzip(b"data"), not_gzip], vec![vec![31, 139, 8]]] {
+            assert_eq!(gzip_concat(members).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
 }
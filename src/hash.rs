@@ -40,12 +40,10 @@ impl HashThing {
         self.val = 0;
         self.head.clear();
         self.head.resize(65536, -1);
-        self.prev_and_hashval = (0..ZOPFLI_WINDOW_SIZE as u16).map(|p| {
-            SmallerHashThing {
-                prev: p,
-                hashval: None,
-            }
-        }).collect();
+        for (p, item) in self.prev_and_hashval.iter_mut().enumerate() {
+            item.prev = p as u16;
+            item.hashval = None;
+        }
     }
 
     fn update(&mut self, hpos: usize) {
@@ -71,6 +69,7 @@ pub struct ZopfliHash {
     hash1: HashThing,
     hash2: HashThing,
     pub same: [u16; ZOPFLI_WINDOW_SIZE],  /* Amount of repetitions of same byte after this .*/
+    sameruns: bool,  /* Whether same is counted, see Options::sameruns. */
 }
 
 impl ZopfliHash {
@@ -79,13 +78,18 @@ impl ZopfliHash {
             hash1: HashThing::new(),
             hash2: HashThing::new(),
             same: [0; ZOPFLI_WINDOW_SIZE],
+            sameruns: true,
         }
     }
 
-    pub fn reset(&mut self) {
+    /// Clears the hash for a new block. Without `sameruns`, runs of the same
+    /// byte aren't counted, so they are matched byte by byte and the second
+    /// hash chains the same positions as the first.
+    pub fn reset(&mut self, sameruns: bool) {
         self.hash1.reset();
         self.hash2.reset();
         self.same = [0; ZOPFLI_WINDOW_SIZE];
+        self.sameruns = sameruns;
     }
 
     pub fn warmup(&mut self, arr: &[u8], pos: usize, end: usize) {
@@ -120,6 +124,12 @@ impl ZopfliHash {
         if same > 1 {
             amount = same - 1;
         }
+        if self.sameruns {
+            while pos + amount as usize + 1 < array.len() &&
+                array[pos] == array[pos + amount as usize + 1] && amount < u16::MAX {
+                amount += 1;
+            }
+        }
 
         self.same[hpos] = amount;
 
@@ -157,3 +167,52 @@ impl ZopfliHash {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prev_chains_link_matching_prefixes() {
+        let data = b"abcXabcYabcZabc";
+        let mut h = ZopfliHash::new();
+        h.warmup(data, 0, data.len());
+        for i in 0..data.len() {
+            h.update(data, i);
+        }
+
+        // Every occurrence of "abc" points back to the previous one, and the
+        // first occurrence points to itself to end the chain.
+        assert_eq!(h.head_at(h.hash_val_at(12, Which::Hash1) as usize, Which::Hash1), 12);
+        assert_eq!(h.prev_at(12, Which::Hash1), 8);
+        assert_eq!(h.prev_at(8, Which::Hash1), 4);
+        assert_eq!(h.prev_at(4, Which::Hash1), 0);
+        assert_eq!(h.prev_at(0, Which::Hash1), 0);
+        for &pos in &[4, 8, 12] {
+            assert_eq!(h.hash_val_at(pos, Which::Hash1), h.hash_val_at(0, Which::Hash1));
+        }
+
+        // Positions with a different 3-byte prefix aren't chained.
+        assert_eq!(h.prev_at(1, Which::Hash1), 1);
+        assert_eq!(h.prev_at(3, Which::Hash1), 3);
+    }
+
+    #[test]
+    fn same_counts_the_rest_of_a_run() {
+        let mut data = vec![b'a'; 10000];
+        data.extend_from_slice(b"bcd");
+        for &sameruns in &[true, false] {
+            let mut h = ZopfliHash::new();
+            h.reset(sameruns);
+            h.warmup(&data, 0, data.len());
+            for i in 0..data.len() {
+                h.update(&data, i);
+            }
+            // Only the last window of positions is still in the hash.
+            for &pos in &[9000, 9990, 9999, 10001] {
+                let expected = if sameruns && pos < 10000 { 10000 - pos - 1 } else { 0 };
+                assert_eq!(h.same[pos & ZOPFLI_WINDOW_MASK] as usize, expected, "position {}", pos);
+            }
+        }
+    }
+}
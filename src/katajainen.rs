@@ -1,5 +1,10 @@
 use std::cell::Cell;
 use std::cmp::Ordering;
+#[cfg(feature = "std")]
+use std::error::Error;
+use std::fmt;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
 
 use typed_arena::Arena;
 
@@ -48,10 +53,56 @@ struct List<'a> {
     lookahead1: &'a Node<'a>,
 }
 
+/// The error of `length_limited_code_lengths`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthLimitError {
+    /// Codes of at most `max_bits` bits can't tell `symbols` symbols apart.
+    TooFewBits { symbols: usize, max_bits: usize },
+}
+
+impl fmt::Display for LengthLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LengthLimitError::TooFewBits { symbols, max_bits } =>
+                write!(f, "{} symbols don't fit in codes of at most {} bits", symbols, max_bits),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for LengthLimitError {}
+
+/// Like `length_limited_code_lengths`, for callers whose `max_bits` is known to
+/// be enough for the number of symbols. Panics if it isn't.
+#[cfg(feature = "std")]
+pub fn limited_code_lengths(frequencies: &[usize], max_bits: usize) -> Vec<u32> {
+    length_limited_code_lengths(frequencies, max_bits)
+        .expect("max_bits is enough for every symbol")
+}
+
+/// Like `limited_code_lengths`, but writes the lengths into `bit_lengths`,
+/// which must have as many entries as `frequencies`.
+#[cfg(feature = "std")]
+pub fn limited_code_lengths_into(frequencies: &[usize], max_bits: usize, bit_lengths: &mut [u32]) {
+    length_limited_code_lengths_into(frequencies, max_bits, bit_lengths)
+        .expect("max_bits is enough for every symbol")
+}
+
 /// Calculates the bitlengths for the Huffman tree, based on the counts of each
-/// symbol.
-pub fn length_limited_code_lengths(frequencies: &[usize], max_bits: usize) -> Vec<u32> {
-    let num_freqs = frequencies.len();
+/// symbol. Fails if `max_bits` is too small to give every symbol with a
+/// non-zero count its own code, that is if there are more than `2^max_bits`
+/// of them.
+pub fn length_limited_code_lengths(frequencies: &[usize], max_bits: usize) -> Result<Vec<u32>, LengthLimitError> {
+    let mut bit_lengths = vec![0; frequencies.len()];
+    length_limited_code_lengths_into(frequencies, max_bits, &mut bit_lengths)?;
+    Ok(bit_lengths)
+}
+
+/// Like `length_limited_code_lengths`, but writes the lengths into
+/// `bit_lengths`, which must have as many entries as `frequencies`, rather than
+/// allocating them.
+pub fn length_limited_code_lengths_into(frequencies: &[usize], max_bits: usize, bit_lengths: &mut [u32]) -> Result<(), LengthLimitError> {
+    assert_eq!(bit_lengths.len(), frequencies.len(), "there must be a length for every frequency");
 
     // Count used symbols and place them in the leaves.
     let mut leaves: Vec<_> = frequencies.iter()
@@ -62,28 +113,27 @@ pub fn length_limited_code_lengths(frequencies: &[usize], max_bits: usize) -> Ve
 
     let num_symbols = leaves.len();
 
-    // Short circuit some special cases
+    if (max_bits as u32) < usize::BITS && (1 << max_bits) < num_symbols {
+        return Err(LengthLimitError::TooFewBits { symbols: num_symbols, max_bits });
+    }
 
-    // TODO:
-    // if ((1 << maxbits) < numsymbols) {
-    //   free(leaves);
-    //   return 1;  /* Error, too few maxbits to represent symbols. */
-    // }
+    // Short circuit some special cases
 
+    for length in bit_lengths.iter_mut() {
+        *length = 0;
+    }
     if num_symbols == 0 {
         // There are no non-zero frequencies.
-        return vec![0; num_freqs];
+        return Ok(());
     }
     if num_symbols == 1 {
-        let mut bit_lengths = vec![0; num_freqs];
         bit_lengths[leaves[0].count] = 1;
-        return bit_lengths;
+        return Ok(());
     }
     if num_symbols == 2 {
-        let mut bit_lengths = vec![0; num_freqs];
         bit_lengths[leaves[0].count] = 1;
         bit_lengths[leaves[1].count] = 1;
-        return bit_lengths;
+        return Ok(());
     }
 
     // Sort the leaves from least frequent to most frequent.
@@ -133,7 +183,8 @@ pub fn length_limited_code_lengths(frequencies: &[usize], max_bits: usize) -> Ve
 
     thing.boundary_pm_final(max_bits - 1);
 
-    thing.extract_bit_lengths(max_bits, num_freqs)
+    thing.extract_bit_lengths(max_bits, bit_lengths);
+    Ok(())
 }
 
 impl<'a> Thing<'a> {
@@ -208,37 +259,24 @@ impl<'a> Thing<'a> {
         }
     }
 
-    fn extract_bit_lengths(&self, max_bits: usize, num_freqs: usize) -> Vec<u32> {
-        let mut counts = [0; 16];
-        let mut end = 16;
-        let mut ptr = 15;
-        let mut value = 1;
-
+    fn extract_bit_lengths(&self, max_bits: usize, bit_lengths: &mut [u32]) {
+        // The chain of the final list holds, from the longest list downwards,
+        // how many of the sorted leaves are active in each list. Leaves that
+        // drop out between two consecutive lists get the same bit length.
+        let mut counts = Vec::with_capacity(max_bits + 1);
         let mut node = self.lists[max_bits - 1].lookahead1;
-
-        end -= 1;
-        counts[end] = node.count;
-
+        counts.push(node.count);
         while let Some(tail) = node.tail.get() {
-            end -= 1;
-            counts[end] = tail.count;
+            counts.push(tail.count);
             node = tail;
         }
+        counts.push(0);
 
-        let mut val = counts[15];
-
-        let mut bit_lengths = vec![0; num_freqs];
-
-        while ptr >= end {
-            while val > counts[ptr - 1] {
-                bit_lengths[self.leaves[val - 1].count] = value;
-                val -= 1;
+        for (value, pair) in counts.windows(2).enumerate() {
+            for leaf in &self.leaves[pair[1]..pair[0]] {
+                bit_lengths[leaf.count] = value as u32 + 1;
             }
-            ptr -= 1;
-            value += 1;
         }
-
-        bit_lengths
     }
 }
 
@@ -317,7 +355,7 @@ mod test {
     #[test]
     fn test_from_paper_3() {
         let input = [1, 1, 5, 7, 10, 14];
-        let output = length_limited_code_lengths(&input, 3);
+        let output = length_limited_code_lengths(&input, 3).unwrap();
         let answer = vec![3, 3, 3, 3, 2, 2];
         assert_eq!(output, answer);
     }
@@ -325,15 +363,24 @@ mod test {
     #[test]
     fn test_from_paper_4() {
         let input = [1, 1, 5, 7, 10, 14];
-        let output = length_limited_code_lengths(&input, 4);
+        let output = length_limited_code_lengths(&input, 4).unwrap();
         let answer = vec![4, 4, 3, 2, 2, 2];
         assert_eq!(output, answer);
     }
 
+    #[test]
+    fn lengths_into_overwrite_the_buffer() {
+        let mut bit_lengths = [9; 6];
+        length_limited_code_lengths_into(&[1, 1, 5, 7, 10, 14], 4, &mut bit_lengths).unwrap();
+        assert_eq!(bit_lengths, [4, 4, 3, 2, 2, 2]);
+        length_limited_code_lengths_into(&[0, 3, 0, 0, 0, 0], 4, &mut bit_lengths).unwrap();
+        assert_eq!(bit_lengths, [0, 1, 0, 0, 0, 0]);
+    }
+
     #[test]
     fn max_bits_7() {
         let input = [252, 0, 1, 6, 9, 10, 6, 3, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-        let output = length_limited_code_lengths(&input, 7);
+        let output = length_limited_code_lengths(&input, 7).unwrap();
         let answer = vec![1, 0, 6, 4, 3, 3, 3, 5, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
         assert_eq!(output, answer);
     }
@@ -341,7 +388,7 @@ mod test {
     #[test]
     fn max_bits_15() {
         let input = [0, 0, 0, 0, 0, 0, 18, 0, 6, 0, 12, 2, 14, 9, 27, 15, 23, 15, 17, 8, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-        let output = length_limited_code_lengths(&input, 15);
+        let output = length_limited_code_lengths(&input, 15).unwrap();
         let answer = vec! [0, 0, 0, 0, 0, 0, 3, 0, 5, 0, 4, 6, 4, 4, 3, 4, 3, 3, 3, 4, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
         assert_eq!(output, answer);
     }
@@ -349,7 +396,7 @@ mod test {
     #[test]
     fn no_frequencies() {
         let input = [0, 0, 0, 0, 0];
-        let output = length_limited_code_lengths(&input, 7);
+        let output = length_limited_code_lengths(&input, 7).unwrap();
         let answer = vec![0, 0, 0, 0, 0];
         assert_eq!(output, answer);
     }
@@ -357,7 +404,7 @@ mod test {
     #[test]
     fn only_one_frequency() {
         let input = [0, 10, 0];
-        let output = length_limited_code_lengths(&input, 7);
+        let output = length_limited_code_lengths(&input, 7).unwrap();
         let answer = vec![0, 1, 0];
         assert_eq!(output, answer);
     }
@@ -365,8 +412,50 @@ mod test {
     #[test]
     fn only_two_frequencies() {
         let input = [0, 0, 0, 0, 252, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-        let output = length_limited_code_lengths(&input, 7);
+        let output = length_limited_code_lengths(&input, 7).unwrap();
         let answer = [0, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
         assert_eq!(output, answer);
     }
+
+    #[test]
+    fn three_frequencies() {
+        // The shortest chain: max_bits is reduced to num_symbols - 1.
+        let input = [1, 0, 1, 1];
+        let output = length_limited_code_lengths(&input, 7).unwrap();
+        let answer = vec![2, 0, 2, 1];
+        assert_eq!(output, answer);
+    }
+
+    #[test]
+    fn too_few_bits_for_symbols() {
+        let input = [1, 2, 3, 4, 5];
+        assert_eq!(length_limited_code_lengths(&input, 2), Err(LengthLimitError::TooFewBits { symbols: 5, max_bits: 2 }));
+        // Unused symbols don't need a code.
+        let input = [1, 0, 3, 0, 5, 7];
+        assert_eq!(length_limited_code_lengths(&input, 2).unwrap(), [2, 0, 2, 0, 2, 2]);
+    }
+
+    fn kraft_sum(bit_lengths: &[u32], max_bits: u32) -> usize {
+        bit_lengths.iter()
+            .filter(|&&l| l != 0)
+            .map(|&l| 1 << (max_bits - l))
+            .sum()
+    }
+
+    #[test]
+    fn limits_fibonacci_frequencies() {
+        // An unrestricted Huffman code for these would be 17 bits deep.
+        let mut input = vec![1, 1];
+        while input.len() < 18 {
+            let next = input[input.len() - 1] + input[input.len() - 2];
+            input.push(next);
+        }
+        for &max_bits in &[7, 15] {
+            let output = length_limited_code_lengths(&input, max_bits).unwrap();
+            assert!(output.iter().all(|&l| l >= 1 && l as usize <= max_bits));
+            assert_eq!(kraft_sum(&output, max_bits as u32), 1 << max_bits);
+            // More frequent symbols never get longer codes.
+            assert!(output.windows(2).all(|w| w[0] >= w[1]));
+        }
+    }
 }
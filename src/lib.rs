@@ -1,31 +1,103 @@
 #![deny(trivial_casts, trivial_numeric_casts)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
+// Without std only the Huffman code construction is available, built on
+// core and alloc. Naming core `std` keeps the paths the same either way.
+#[cfg(not(feature = "std"))]
+extern crate core as std;
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
+
+#[cfg(feature = "std")]
 extern crate adler32;
+#[cfg(feature = "std")]
 extern crate byteorder;
+#[cfg(feature = "std")]
 extern crate crc;
 extern crate typed_arena;
 
+#[cfg(feature = "rayon")]
+extern crate rayon;
+
+#[cfg(all(test, feature = "std"))]
+extern crate flate2;
+
+#[cfg(feature = "std")]
 mod iter;
+#[cfg(feature = "std")]
 mod blocksplitter;
+#[cfg(feature = "std")]
 mod cache;
+#[cfg(feature = "std")]
+mod context;
+#[cfg(feature = "std")]
 mod deflate;
+#[cfg(feature = "std")]
+mod encoder;
+#[cfg(feature = "std")]
 mod gzip;
+#[cfg(feature = "std")]
 mod hash;
 mod katajainen;
+#[cfg(feature = "std")]
 mod lz77;
+#[cfg(feature = "std")]
+pub mod png;
+#[cfg(feature = "std")]
 mod squeeze;
+#[cfg(feature = "std")]
 mod symbols;
 mod tree;
 mod util;
+#[cfg(feature = "std")]
 mod zlib;
 
-use std::io::{self, Write};
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+#[cfg(feature = "std")]
+use std::path::Path;
+
+#[cfg(feature = "std")]
+use deflate::{deflate, deflate_size, BlockType};
+#[cfg(feature = "std")]
+use gzip::{gzip_compress, HEADER};
+#[cfg(feature = "std")]
+use squeeze::{Scratch, Workspace};
+#[cfg(feature = "std")]
+use util::CountingWriter;
+#[cfg(feature = "std")]
+use zlib::{zlib_compress, zlib_compress_with_dictionary};
+
+#[cfg(feature = "std")]
+pub use context::ZopfliContext;
+#[cfg(feature = "std")]
+pub use deflate::DynamicTree;
+#[cfg(feature = "std")]
+pub use encoder::ZopfliEncoder;
+#[cfg(feature = "std")]
+pub use gzip::{crc32_combine, gzip_concat, gzip_concat_trailer};
+#[cfg(feature = "rayon")]
+pub use gzip::crc32_parallel;
+pub use katajainen::{length_limited_code_lengths, LengthLimitError};
+pub use tree::{lengths_to_symbols, validate_code_lengths, KraftError};
+#[cfg(feature = "std")]
+pub use squeeze::{RanState, SymbolStats};
+#[cfg(feature = "std")]
+pub use tree::{calculate_entropy, histogram_cost};
 
-use deflate::{deflate, BlockType};
-use gzip::gzip_compress;
-use zlib::zlib_compress;
+/// Internals used by the benchmarks in `benches/`. Not part of the public API,
+/// they can change at any time.
+#[cfg(feature = "std")]
+#[doc(hidden)]
+pub mod bench {
+    pub use cache::ZopfliLongestMatchCache;
+    pub use deflate::{calculate_block_symbol_size, dynamic_tree, BitwiseWriter};
+    pub use lz77::{LitLen, Lz77Command, Lz77Store};
+}
 
 /// Options used throughout the program.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Options {
   /* Whether to print output */
   pub verbose: bool,
@@ -38,10 +110,68 @@ pub struct Options {
   */
   numiterations: i32,
   /*
+  If true, splits the data in multiple deflate blocks with optimal choice
+  for the block boundaries. Block splitting gives better compression. Default:
+  true.
+  */
+  blocksplitting: bool,
+  /*
   Maximum amount of blocks to split into (0 for unlimited, but this can give
   extreme results that hurt compression on some files). Default value: 15.
   */
   blocksplittingmax: i32,
+  /*
+  If true, the cost model is never randomized between iterations, so the
+  result only depends on the input and numiterations. This usually gives
+  slightly worse compression. Default: false.
+  */
+  deterministic: bool,
+  /*
+  Stop the iterations early once this many of them in a row didn't improve
+  on the best result so far. 0 always does all numiterations. Default: 0.
+  */
+  max_stagnant_iterations: i32,
+  /*
+  Size of the master blocks the input is divided into. Each is compressed on
+  its own, with only the window before it as context, which bounds the memory
+  used. Smaller master blocks use less memory but compress worse. Default:
+  ZOPFLI_MASTER_BLOCK_SIZE.
+  */
+  masterblocksize: usize,
+  /*
+  Whether the greedy LZ77 parser, used for block splitting and as the first
+  guess of the optimal parse, defers a match by one byte when the next byte
+  starts a longer one. Turning it off is a little faster. Default: true.
+  */
+  lazy_matching: bool,
+  /*
+  Matches are never further back than this many bytes, for decoders with less
+  memory for the window than deflate allows. Default: ZOPFLI_WINDOW_SIZE.
+  */
+  max_distance: usize,
+  /*
+  Matches are never longer than this, for measuring how much the longest
+  matches matter. Default: ZOPFLI_MAX_MATCH, the longest deflate allows.
+  */
+  max_match: usize,
+  /*
+  After block splitting, merge adjacent blocks that are estimated to be smaller
+  as one, see `blocksplitter::merge_blocks`. Default: false.
+  */
+  merge_blocks: bool,
+  /*
+  Also split the master block after its optimal parse as a whole, rather than
+  only parsing the blocks found on the greedy parse, and keep whichever is
+  estimated smaller. Costs about one more optimal parse. Default: false.
+  */
+  blocksplittinglast: bool,
+  /*
+  Count runs of the same byte in the hash, so that matches within them are
+  found without comparing every byte and the second hash, keyed on the run
+  length, can skip past them. Only turn it off to measure its effect.
+  Default: true.
+  */
+  sameruns: bool,
 }
 
 impl Default for Options {
@@ -50,23 +180,502 @@ impl Default for Options {
             verbose: false,
             verbose_more: false,
             numiterations: 15,
+            blocksplitting: true,
             blocksplittingmax: 15,
+            deterministic: false,
+            max_stagnant_iterations: 0,
+            masterblocksize: util::ZOPFLI_MASTER_BLOCK_SIZE,
+            lazy_matching: true,
+            max_distance: util::ZOPFLI_WINDOW_SIZE,
+            max_match: util::ZOPFLI_MAX_MATCH,
+            merge_blocks: false,
+            blocksplittinglast: false,
+            sameruns: true,
         }
     }
 }
 
+/// Builds `Options`, starting from the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+impl OptionsBuilder {
+    pub fn new() -> OptionsBuilder {
+        OptionsBuilder::default()
+    }
+
+    pub fn verbose(mut self, verbose: bool) -> OptionsBuilder {
+        self.options.verbose = verbose;
+        self
+    }
+
+    pub fn verbose_more(mut self, verbose_more: bool) -> OptionsBuilder {
+        self.options.verbose_more = verbose_more;
+        self
+    }
+
+    pub fn numiterations(mut self, numiterations: i32) -> OptionsBuilder {
+        self.options.numiterations = numiterations;
+        self
+    }
+
+    pub fn blocksplitting(mut self, blocksplitting: bool) -> OptionsBuilder {
+        self.options.blocksplitting = blocksplitting;
+        self
+    }
+
+    pub fn blocksplittingmax(mut self, blocksplittingmax: i32) -> OptionsBuilder {
+        self.options.blocksplittingmax = blocksplittingmax;
+        self
+    }
+
+    pub fn deterministic(mut self, deterministic: bool) -> OptionsBuilder {
+        self.options.deterministic = deterministic;
+        self
+    }
+
+    pub fn max_stagnant_iterations(mut self, max_stagnant_iterations: i32) -> OptionsBuilder {
+        self.options.max_stagnant_iterations = max_stagnant_iterations;
+        self
+    }
+
+    /// Panics if `masterblocksize` is 0.
+    pub fn masterblocksize(mut self, masterblocksize: usize) -> OptionsBuilder {
+        assert!(masterblocksize > 0, "the master block size must be positive");
+        self.options.masterblocksize = masterblocksize;
+        self
+    }
+
+    pub fn lazy_matching(mut self, lazy_matching: bool) -> OptionsBuilder {
+        self.options.lazy_matching = lazy_matching;
+        self
+    }
+
+    /// Panics if `max_distance` is 0 or more than the deflate window size.
+    pub fn max_distance(mut self, max_distance: usize) -> OptionsBuilder {
+        assert!(max_distance > 0 && max_distance <= util::ZOPFLI_WINDOW_SIZE,
+                "the maximum distance must be between 1 and the window size");
+        self.options.max_distance = max_distance;
+        self
+    }
+
+    /// Panics if `max_match` is shorter than the shortest or longer than the
+    /// longest match deflate allows.
+    pub fn max_match(mut self, max_match: usize) -> OptionsBuilder {
+        assert!((util::ZOPFLI_MIN_MATCH..=util::ZOPFLI_MAX_MATCH).contains(&max_match),
+                "the maximum match length must be between 3 and 258");
+        self.options.max_match = max_match;
+        self
+    }
+
+    pub fn merge_blocks(mut self, merge_blocks: bool) -> OptionsBuilder {
+        self.options.merge_blocks = merge_blocks;
+        self
+    }
+
+    pub fn blocksplittinglast(mut self, blocksplittinglast: bool) -> OptionsBuilder {
+        self.options.blocksplittinglast = blocksplittinglast;
+        self
+    }
+
+    pub fn sameruns(mut self, sameruns: bool) -> OptionsBuilder {
+        self.options.sameruns = sameruns;
+        self
+    }
+
+    pub fn build(self) -> Options {
+        self.options
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Format {
     Gzip,
     Zlib,
     Deflate,
 }
 
+impl Format {
+    /// Infers the container from the extension of `path`, ignoring case: `gz`
+    /// is gzip, `zlib` and `zz` are zlib, and `deflate` or no extension at all
+    /// is raw deflate. Returns `None` for any other extension.
+    #[cfg(feature = "std")]
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let extension = match path.extension() {
+            Some(extension) => extension.to_str()?.to_ascii_lowercase(),
+            None => return Some(Format::Deflate),
+        };
+        match &*extension {
+            "gz" => Some(Format::Gzip),
+            "zlib" | "zz" => Some(Format::Zlib),
+            "deflate" => Some(Format::Deflate),
+            _ => None,
+        }
+    }
+
+    /// The usual file extension for this container, without the dot.
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Format::Gzip => "gz",
+            Format::Zlib => "zlib",
+            Format::Deflate => "deflate",
+        }
+    }
+}
+
+/// Progress reported to the callback of `compress_with_progress`. Positions are
+/// byte offsets into the input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressEvent {
+    /// The master block `start..end` was split into `blocks` deflate blocks.
+    BlocksSplit { start: usize, end: usize, blocks: usize },
+    /// An iteration of the optimal parse of the block `start..end` improved its
+    /// estimated compressed size, in bits.
+    Improved { start: usize, end: usize, iteration: i32, bits: f64 },
+}
+
+#[cfg(feature = "std")]
 pub fn compress<W>(options: &Options, output_type: &Format, in_data: &[u8], out: W) -> io::Result<()>
     where W: Write
 {
+    compress_with_progress(options, output_type, in_data, out, &mut |_| {})
+}
+
+/// Like `compress`, but calls `progress` as the compression goes on. With the
+/// `rayon` feature, the events of each block are reported once all blocks of
+/// the master block are done.
+#[cfg(feature = "std")]
+pub fn compress_with_progress<W>(options: &Options, output_type: &Format, in_data: &[u8], out: W, progress: &mut dyn FnMut(ProgressEvent)) -> io::Result<()>
+    where W: Write
+{
+    compress_with_workspace(options, output_type, in_data, out, &mut Workspace { scratch: &mut Scratch::new(), progress })
+}
+
+#[cfg(feature = "std")]
+fn compress_with_workspace<W>(options: &Options, output_type: &Format, in_data: &[u8], out: W, work: &mut Workspace) -> io::Result<()>
+    where W: Write
+{
+    match *output_type {
+        Format::Gzip => gzip_compress(options, in_data, out, work),
+        Format::Zlib => zlib_compress(options, in_data, out, work),
+        Format::Deflate => deflate(options, BlockType::Dynamic, in_data, out, work),
+    }
+}
+
+/// Compresses everything `reader` yields into `writer`, using a
+/// `ZopfliEncoder` so that only a master block and the window before it are
+/// held in memory at once. Returns the number of compressed bytes written.
+#[cfg(feature = "std")]
+pub fn compress_stream<R, W>(options: &Options, output_type: &Format, mut reader: R, writer: W) -> io::Result<u64>
+    where R: Read, W: Write
+{
+    let mut encoder = ZopfliEncoder::new(options.clone(), *output_type, CountingWriter::new(writer));
+    io::copy(&mut reader, &mut encoder)?;
+    let mut out = encoder.finish()?;
+    out.flush()?;
+    Ok(out.count)
+}
+
+/// Compresses `in_data` to a zlib stream for a decoder that has `dictionary` as
+/// its preset dictionary, so that data much like the dictionary compresses to
+/// very little. The decoder needs the same dictionary to decompress it.
+#[cfg(feature = "std")]
+pub fn compress_zlib_with_dictionary(options: &Options, dictionary: &[u8], in_data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    zlib_compress_with_dictionary(options, dictionary, in_data, &mut out, &mut Workspace { scratch: &mut Scratch::new(), progress: &mut |_| {} })
+        .expect("writing to a Vec never fails");
+    out
+}
+
+/// Returns the length `compress` would produce for `in_data`. All the same
+/// work of picking the blocks is done, but their sizes are calculated rather
+/// than written out.
+#[cfg(feature = "std")]
+pub fn estimate_compressed_size(options: &Options, output_type: &Format, in_data: &[u8]) -> usize {
+    let deflated = deflate_size(options, in_data, &mut Workspace { scratch: &mut Scratch::new(), progress: &mut |_| {} });
     match *output_type {
-        Format::Gzip => gzip_compress(options, in_data, out),
-        Format::Zlib => zlib_compress(options, in_data, out),
-        Format::Deflate => deflate(options, BlockType::Dynamic, in_data, out),
+        /* The CRC-32 and ISIZE fields follow the deflate data. */
+        Format::Gzip => HEADER.len() + deflated + 8,
+        /* A 2 byte header and the Adler-32 of the data. */
+        Format::Zlib => 2 + deflated + 4,
+        Format::Deflate => deflated,
+    }
+}
+
+/// Like `compress`, but returns the compressed data in a newly allocated buffer.
+#[cfg(feature = "std")]
+pub fn compress_to_vec(options: &Options, output_type: &Format, in_data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    compress(options, output_type, in_data, &mut out).expect("writing to a Vec never fails");
+    out
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+
+    use util::pseudo_random_bytes;
+    use super::*;
+
+    fn decompress(output_type: &Format, compressed: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        match *output_type {
+            Format::Gzip => GzDecoder::new(compressed).read_to_end(&mut out),
+            Format::Zlib => ZlibDecoder::new(compressed).read_to_end(&mut out),
+            Format::Deflate => DeflateDecoder::new(compressed).read_to_end(&mut out),
+        }.expect("invalid compressed data");
+        out
+    }
+
+    #[test]
+    fn empty_input_round_trips_with_empty_checksums() {
+        let options = Options::default();
+        for output_type in &[Format::Gzip, Format::Zlib, Format::Deflate] {
+            let compressed = compress_to_vec(&options, output_type, &[]);
+            assert!(decompress(output_type, &compressed).is_empty());
+            let trailer = &compressed[compressed.len().saturating_sub(8)..];
+            match *output_type {
+                // CRC-32 and size of no bytes.
+                Format::Gzip => assert_eq!(trailer, [0; 8]),
+                // Adler-32 of no bytes.
+                Format::Zlib => assert_eq!(&trailer[4..], [0, 0, 0, 1]),
+                Format::Deflate => {},
+            }
+        }
+    }
+
+    #[test]
+    fn estimated_size_matches_compressed_length() {
+        let options = OptionsBuilder::new().numiterations(2).build();
+        let inputs: Vec<Vec<u8>> = vec![
+            vec![],
+            b"x".to_vec(),
+            b"An estimate that has to be exact, an estimate that has to be exact. ".repeat(8),
+            (0..1000u32).map(|i| ((i * 7919) >> 3) as u8).collect(),
+            pseudo_random_bytes(200, 1),
+        ];
+        for data in &inputs {
+            for output_type in &[Format::Gzip, Format::Zlib, Format::Deflate] {
+                assert_eq!(estimate_compressed_size(&options, output_type, data),
+                           compress_to_vec(&options, output_type, data).len());
+            }
+        }
+    }
+
+    #[test]
+    fn compress_stream_round_trips() {
+        let options = OptionsBuilder::new().numiterations(5).build();
+        let data: Vec<u8> = b"Compressed straight from a reader into a writer. "
+            .iter().cycle().take(3000).cloned().collect();
+
+        for output_type in &[Format::Gzip, Format::Zlib, Format::Deflate] {
+            let mut out = io::Cursor::new(vec![]);
+            let written = compress_stream(&options, output_type, io::Cursor::new(&data), &mut out).unwrap();
+            let compressed = out.into_inner();
+            assert_eq!(written, compressed.len() as u64);
+            assert_eq!(decompress(output_type, &compressed), data);
+        }
+    }
+
+    #[test]
+    fn compress_stream_reports_read_errors() {
+        struct FailingReader;
+        impl Read for FailingReader {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "read failed"))
+            }
+        }
+
+        let result = compress_stream(&Options::default(), &Format::Gzip, FailingReader, vec![]);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn varying_master_block_size_round_trips() {
+        let text = b"Each master block is compressed on its own. ";
+        let mut data = text.repeat(30);
+        data.extend((0..500u32).map(|i| (i * i) as u8));
+        data.extend(text.repeat(30));
+
+        for &masterblocksize in &[100, 1000, data.len(), data.len() + 1] {
+            let options = OptionsBuilder::new().numiterations(2).masterblocksize(masterblocksize).build();
+            for output_type in &[Format::Gzip, Format::Zlib, Format::Deflate] {
+                let compressed = compress_to_vec(&options, output_type, &data);
+                assert!(decompress(output_type, &compressed) == data, "master block size {}", masterblocksize);
+            }
+        }
+    }
+
+    #[test]
+    fn tiny_master_blocks_round_trip() {
+        // Every master block sets up its own hash and cache, so keep this short.
+        let data = b"abcabcabc, tiny master blocks. abcabcabc!";
+        for &masterblocksize in &[1, 7] {
+            let options = OptionsBuilder::new().numiterations(1).masterblocksize(masterblocksize).build();
+            let compressed = compress_to_vec(&options, &Format::Deflate, data);
+            assert_eq!(decompress(&Format::Deflate, &compressed), data.to_vec());
+        }
+    }
+
+    #[test]
+    fn single_master_block_matches_default() {
+        let data = b"The default master block size covers this whole input.".repeat(10);
+        let options = OptionsBuilder::new().numiterations(5).build();
+        let single = OptionsBuilder::new().numiterations(5).masterblocksize(data.len()).build();
+        assert_eq!(compress_to_vec(&options, &Format::Deflate, &data), compress_to_vec(&single, &Format::Deflate, &data));
+    }
+
+    #[test]
+    fn blocksplittinglast_never_grows_output() {
+        let text = b"Split before the parse, or after it, whichever is smaller. ";
+        let mut mixed = text.repeat(8);
+        mixed.extend(pseudo_random_bytes(500, 5));
+        mixed.extend(text.repeat(8));
+        let numbers: Vec<u8> = (0..300u32).flat_map(|i| format!("{},", i * i % 977).into_bytes()).collect();
+
+        let before = OptionsBuilder::new().numiterations(2).build();
+        let last = OptionsBuilder::new().numiterations(2).blocksplittinglast(true).build();
+        for data in &[mixed, numbers] {
+            let compressed = compress_to_vec(&last, &Format::Deflate, data);
+            assert!(decompress(&Format::Deflate, &compressed) == *data);
+            assert!(compressed.len() <= compress_to_vec(&before, &Format::Deflate, data).len());
+        }
+    }
+
+    #[test]
+    fn limited_max_match_round_trips() {
+        let mut data = b"Short matches only, short matches only. ".repeat(10);
+        data.extend(vec![0; 1000]);
+        let options = OptionsBuilder::new().numiterations(5).max_match(16).build();
+        for output_type in &[Format::Gzip, Format::Zlib, Format::Deflate] {
+            let compressed = compress_to_vec(&options, output_type, &data);
+            assert!(decompress(output_type, &compressed) == data);
+            assert!(compressed.len() > compress_to_vec(&Options::default(), output_type, &data).len());
+        }
+    }
+
+    #[test]
+    fn limited_max_distance_round_trips() {
+        let text = b"A window of only a hundred bytes still finds the nearby repeats. ";
+        let data = text.repeat(20);
+        let options = OptionsBuilder::new().numiterations(5).max_distance(100).build();
+        for output_type in &[Format::Gzip, Format::Zlib, Format::Deflate] {
+            let compressed = compress_to_vec(&options, output_type, &data);
+            assert!(compressed.len() < data.len() / 4);
+            assert!(decompress(output_type, &compressed) == data);
+        }
+    }
+
+    #[test]
+    fn format_from_path() {
+        let cases = [
+            ("file.gz", Some(Format::Gzip)),
+            ("FILE.GZ", Some(Format::Gzip)),
+            ("file.zlib", Some(Format::Zlib)),
+            ("file.ZZ", Some(Format::Zlib)),
+            ("dir.gz/file.deflate", Some(Format::Deflate)),
+            ("file.Deflate", Some(Format::Deflate)),
+            ("file", Some(Format::Deflate)),
+            (".gz", Some(Format::Deflate)),
+            ("file.tar", None),
+            ("file.gz.bak", None),
+        ];
+        for &(path, expected) in &cases {
+            assert_eq!(Format::from_path(Path::new(path)), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn format_extension_round_trips() {
+        for &output_type in &[Format::Gzip, Format::Zlib, Format::Deflate] {
+            let path = format!("file.{}", output_type.extension());
+            assert_eq!(Format::from_path(Path::new(&path)), Some(output_type));
+        }
+    }
+
+    #[test]
+    fn compress_to_vec_round_trips() {
+        let options = Options::default();
+        let data = b"The quick brown fox jumps over the lazy dog. The quick brown fox jumps \
+                     over the lazy dog, and then the lazy dog jumps over the quick brown fox.";
+
+        for output_type in &[Format::Gzip, Format::Zlib, Format::Deflate] {
+            let compressed = compress_to_vec(&options, output_type, data);
+            assert!(compressed.len() < data.len());
+            assert_eq!(decompress(output_type, &compressed), data.to_vec());
+        }
+    }
+
+    #[test]
+    fn options_builder() {
+        assert_eq!(OptionsBuilder::new().build(), Options::default());
+
+        let options = OptionsBuilder::new()
+            .numiterations(5)
+            .blocksplitting(false)
+            .blocksplittingmax(3)
+            .build();
+        assert_eq!(options.numiterations, 5);
+        assert!(!options.blocksplitting);
+        assert_eq!(options.blocksplittingmax, 3);
+        assert_eq!(options.verbose, Options::default().verbose);
+    }
+
+    #[test]
+    fn compress_without_blocksplitting() {
+        let options = OptionsBuilder::new().blocksplitting(false).build();
+        let data: Vec<u8> = (0..5000).map(|i| (i * i % 7 + i / 1000) as u8).collect();
+
+        let compressed = compress_to_vec(&options, &Format::Deflate, &data);
+        assert_eq!(decompress(&Format::Deflate, &compressed), data);
+    }
+
+    #[test]
+    fn deterministic_compression() {
+        // Enough iterations for the cost model to stabilize and be randomized.
+        let builder = OptionsBuilder::new().numiterations(30);
+        let deterministic = builder.clone().deterministic(true).build();
+        let data: Vec<u8> = (0..4000).map(|i| b"abracadabra"[i % 11] ^ ((i / 97) as u8 & 3)).collect();
+
+        let first = compress_to_vec(&deterministic, &Format::Deflate, &data);
+        let second = compress_to_vec(&deterministic, &Format::Deflate, &data);
+        assert_eq!(first, second);
+        assert_eq!(decompress(&Format::Deflate, &first), data);
+
+        let randomized = compress_to_vec(&builder.build(), &Format::Deflate, &data);
+        assert_eq!(decompress(&Format::Deflate, &randomized), data);
+    }
+
+    #[test]
+    fn reports_progress() {
+        let options = OptionsBuilder::new().numiterations(10).blocksplitting(false).build();
+        let data: Vec<u8> = (0..3000).map(|i| b"progress"[i % 8] ^ ((i / 300) as u8)).collect();
+        let mut events = vec![];
+        let mut out = vec![];
+        compress_with_progress(&options, &Format::Zlib, &data, &mut out, &mut |event| events.push(event)).unwrap();
+        assert_eq!(decompress(&Format::Zlib, &out), data);
+
+        assert_eq!(events[0], ProgressEvent::BlocksSplit { start: 0, end: data.len(), blocks: 1 });
+        let improvements: Vec<_> = events[1..].iter().map(|&event| match event {
+            ProgressEvent::Improved { start: 0, end: 3000, iteration, bits } => (iteration, bits),
+            _ => panic!("unexpected event {:?}", event),
+        }).collect();
+        // The first iteration always improves on nothing.
+        assert_eq!(improvements[0].0, 0);
+        assert!(improvements.len() <= 10);
+        assert!(improvements.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 >= w[1].1));
+    }
+
+    #[test]
+    fn stops_early_without_improvement() {
+        let data = b"aaaaaaaaaabbbbbbbbbbaaaaaaaaaabbbbbbbbbb".repeat(20);
+        let builder = OptionsBuilder::new().deterministic(true);
+        let expected = compress_to_vec(&builder.clone().numiterations(15).build(), &Format::Gzip, &data);
+
+        // Would take very long if every iteration was run.
+        let options = builder.numiterations(i32::MAX).max_stagnant_iterations(3).build();
+        assert_eq!(compress_to_vec(&options, &Format::Gzip, &data), expected);
     }
 }
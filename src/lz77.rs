@@ -1,12 +1,16 @@
 use std::cmp;
+use std::error::Error;
+use std::fmt;
 
 use cache::{ZopfliLongestMatchCache, Cache, NoCache};
+#[cfg(feature = "cache-stats")]
+use cache::CacheStats;
 use hash::{ZopfliHash, Which};
 use symbols::{get_dist_symbol, get_length_symbol};
 use util::{ZOPFLI_NUM_LL, ZOPFLI_NUM_D, ZOPFLI_MAX_MATCH, ZOPFLI_MIN_MATCH, ZOPFLI_WINDOW_MASK, ZOPFLI_MAX_CHAIN_HITS, ZOPFLI_WINDOW_SIZE};
 use Options;
 
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
 pub enum LitLen {
     Literal(u16),
     LengthDist(u16, u16),
@@ -21,12 +25,50 @@ impl LitLen {
     }
 }
 
+/// One item of an `Lz77Store`, in the form `Lz77Store::lit_len_dist` takes:
+/// a literal when `dist` is 0, otherwise a match of length `litlen`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lz77Command {
+    pub litlen: u16,
+    pub dist: u16,
+    /* Position of the item in the input. */
+    pub pos: usize,
+}
+
+/// Where `Lz77Store::verify` found the store to disagree with the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The item at `index` starts at `pos` rather than where the item before
+    /// it ended.
+    Gap { index: usize, pos: usize },
+    /// The match at `pos` refers to `dist` bytes back, before the data starts.
+    DistanceTooFar { pos: usize, dist: u16 },
+    /// The store reproduces a different byte at `pos` than the data has, or
+    /// goes on past its end.
+    Mismatch { pos: usize },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VerifyError::Gap { index, pos } => write!(f, "item {} starts at {}, not where the previous one ended", index, pos),
+            VerifyError::DistanceTooFar { pos, dist } => write!(f, "match at {} has distance {}, before the start of the data", pos, dist),
+            VerifyError::Mismatch { pos } => write!(f, "reconstructed data differs at {}", pos),
+        }
+    }
+}
+
+impl Error for VerifyError {}
+
 /// Stores lit/length and dist pairs for LZ77.
 /// Parameter litlens: Contains the literal symbols or length values.
 /// Parameter dists: Contains the distances. A value is 0 to indicate that there is
 /// no dist and the corresponding litlens value is a literal instead of a length.
 /// Parameter size: The size of both the litlens and dists arrays.
-#[derive(Debug, Clone, Default)]
+///
+/// Its `Debug` output is a summary, see `summary`; the alternate form (`{:#?}`)
+/// prints every field in full.
+#[derive(Clone, Default)]
 pub struct Lz77Store {
    pub litlens: Vec<LitLen>,
 
@@ -54,6 +96,26 @@ impl Lz77Store {
        }
     }
 
+    /// Creates an empty store with room for `symbol_count` symbols, so that
+    /// appending up to that many doesn't reallocate.
+    pub fn with_capacity(symbol_count: usize) -> Lz77Store {
+        // One histogram is kept per started chunk of ZOPFLI_NUM_LL (or
+        // ZOPFLI_NUM_D) symbols.
+        let ll_chunks = symbol_count.div_ceil(ZOPFLI_NUM_LL);
+        let d_chunks = symbol_count.div_ceil(ZOPFLI_NUM_D);
+        Lz77Store {
+          litlens: Vec::with_capacity(symbol_count),
+
+          pos: Vec::with_capacity(symbol_count),
+
+          ll_symbol: Vec::with_capacity(symbol_count),
+          d_symbol: Vec::with_capacity(symbol_count),
+
+          ll_counts: Vec::with_capacity(ll_chunks * ZOPFLI_NUM_LL),
+          d_counts: Vec::with_capacity(d_chunks * ZOPFLI_NUM_D),
+       }
+    }
+
     pub fn reset(&mut self) {
         self.litlens.clear();
         self.pos.clear();
@@ -67,6 +129,20 @@ impl Lz77Store {
         self.litlens.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.litlens.is_empty()
+    }
+
+    /// The literal/length symbol of each item.
+    pub fn ll_symbols(&self) -> &[u16] {
+        &self.ll_symbol
+    }
+
+    /// The distance symbol of each item, 0 for literals.
+    pub fn d_symbols(&self) -> &[u16] {
+        &self.d_symbol
+    }
+
     pub fn append_store_item(&mut self, litlen: LitLen, pos: usize) {
         let origsize = self.litlens.len();
         let llstart = ZOPFLI_NUM_LL * (origsize / ZOPFLI_NUM_LL);
@@ -112,6 +188,65 @@ impl Lz77Store {
         }
     }
 
+    /// Appends all items of `other`, like calling `append_store_item` for each
+    /// of them, but building the cumulative histograms in one batch.
+    pub fn append_store(&mut self, other: &Lz77Store) {
+        let origsize = self.size();
+        self.litlens.extend_from_slice(&other.litlens);
+        self.pos.extend_from_slice(&other.pos);
+        self.ll_symbol.extend_from_slice(&other.ll_symbol);
+        self.d_symbol.extend_from_slice(&other.d_symbol);
+        self.build_histograms(origsize);
+    }
+
+    /// Appends the items `lstart..lend` of `other`, with their positions moved
+    /// so that the first one starts where the last item of this store ends, or
+    /// at 0 if it is empty.
+    pub fn append_range(&mut self, other: &Lz77Store, lstart: usize, lend: usize) {
+        assert!(lstart <= lend && lend <= other.size());
+        if lstart == lend {
+            return;
+        }
+        let origsize = self.size();
+        let start = match (self.litlens.last(), self.pos.last()) {
+            (Some(litlen), Some(&pos)) => pos + litlen.size(),
+            _ => 0,
+        };
+        let offset = other.pos[lstart];
+        self.litlens.extend_from_slice(&other.litlens[lstart..lend]);
+        self.pos.extend(other.pos[lstart..lend].iter().map(|&pos| pos - offset + start));
+        self.ll_symbol.extend_from_slice(&other.ll_symbol[lstart..lend]);
+        self.d_symbol.extend_from_slice(&other.d_symbol[lstart..lend]);
+        self.build_histograms(origsize);
+    }
+
+    /// Extends the cumulative histograms with the counts of the items from
+    /// `start` on, whose symbols must already be stored.
+    fn build_histograms(&mut self, start: usize) {
+        add_cumulative_counts(&mut self.ll_counts, ZOPFLI_NUM_LL, &self.ll_symbol, start, |_| 0);
+        // Literals have distance symbol 0, they are counted and then taken out
+        // again, which avoids a branch per item.
+        let litlens = &self.litlens;
+        add_cumulative_counts(&mut self.d_counts, ZOPFLI_NUM_D, &self.d_symbol, start, |range| {
+            litlens[range].iter().filter(|litlen| match **litlen {
+                LitLen::Literal(_) => true,
+                LitLen::LengthDist(..) => false,
+            }).count()
+        });
+    }
+
+    /// Iterates over the items in order, as the commands that would build the
+    /// same store with `lit_len_dist`.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = Lz77Command> + 'a {
+        self.litlens.iter().zip(self.pos.iter()).map(|(&litlen, &pos)| {
+            let (litlen, dist) = match litlen {
+                LitLen::Literal(lit) => (lit, 0),
+                LitLen::LengthDist(len, dist) => (len, dist),
+            };
+            Lz77Command { litlen, dist, pos }
+        })
+    }
+
     pub fn lit_len_dist(&mut self, length: u16, dist: u16, pos: usize) {
         let litlen = if dist == 0 {
             LitLen::Literal(length)
@@ -122,19 +257,27 @@ impl Lz77Store {
         self.append_store_item(litlen, pos);
     }
 
-    /// Does LZ77 using an algorithm similar to gzip, with lazy matching, rather than
-    /// with the slow but better "squeeze" implementation.
+    /// Does LZ77 using an algorithm similar to gzip, with lazy matching unless the
+    /// options turn it off, rather than with the slow but better "squeeze"
+    /// implementation.
     /// The result is placed in the Lz77Store.
     /// If instart is larger than 0, it uses values before instart as starting
     /// dictionary.
     pub fn greedy<C>(&mut self, s: &mut ZopfliBlockState<C>, in_data: &[u8], instart: usize, inend: usize)
         where C: Cache,
+    {
+        self.greedy_with_hash(s, &mut ZopfliHash::new(), in_data, instart, inend);
+    }
+
+    /// Like `greedy`, but uses `h` rather than allocating a new hash.
+    pub fn greedy_with_hash<C>(&mut self, s: &mut ZopfliBlockState<C>, h: &mut ZopfliHash, in_data: &[u8], instart: usize, inend: usize)
+        where C: Cache,
     {
         if instart == inend {
             return;
         }
         let windowstart = instart.saturating_sub(ZOPFLI_WINDOW_SIZE);
-        let mut h = ZopfliHash::new();
+        h.reset(s.options.sameruns);
 
         let arr = &in_data[..inend];
         h.warmup(arr, windowstart, inend);
@@ -148,7 +291,9 @@ impl Lz77Store {
         let mut dist;
         let mut lengthscore;
 
-        /* Lazy matching. */
+        /* Lazy matching. Without it, match_available is never set. */
+        let lazy_matching = s.options.lazy_matching;
+        let max_match = s.options.max_match;
         let mut prev_length = 0;
         let mut prev_match = 0;
         let mut prevlengthscore;
@@ -156,7 +301,7 @@ impl Lz77Store {
         while i < inend {
             h.update(arr, i);
 
-            let longest_match = find_longest_match(s, &mut h, arr, i, inend, ZOPFLI_MAX_MATCH, &mut None);
+            let longest_match = find_longest_match(s, h, arr, i, inend, max_match, &mut None);
             dist = longest_match.distance;
             leng = longest_match.length;
             lengthscore = get_length_score(leng as i32, dist as i32);
@@ -167,7 +312,7 @@ impl Lz77Store {
                 match_available = false;
                 if lengthscore > prevlengthscore + 1 {
                     self.lit_len_dist(arr[i - 1] as u16, 0, i - 1);
-                    if (lengthscore as usize) >= ZOPFLI_MIN_MATCH && (leng as usize) < ZOPFLI_MAX_MATCH {
+                    if (lengthscore as usize) >= ZOPFLI_MIN_MATCH && (leng as usize) < max_match {
                         match_available = true;
                         prev_length = leng as u32;
                         prev_match = dist as u32;
@@ -189,7 +334,7 @@ impl Lz77Store {
                      i += 1;
                      continue;
                 }
-            } else if (lengthscore as usize) >= ZOPFLI_MIN_MATCH && (leng as usize) < ZOPFLI_MAX_MATCH {
+            } else if lazy_matching && (lengthscore as usize) >= ZOPFLI_MIN_MATCH && (leng as usize) < max_match {
                 match_available = true;
                 prev_length = leng as u32;
                 prev_match = dist as u32;
@@ -215,7 +360,7 @@ impl Lz77Store {
         }
     }
 
-    pub fn follow_path<C>(&mut self, in_data: &[u8], instart: usize, inend: usize, path: Vec<u16>, s: &mut ZopfliBlockState<C>)
+    pub fn follow_path<C>(&mut self, in_data: &[u8], instart: usize, inend: usize, path: Vec<u16>, s: &mut ZopfliBlockState<C>, h: &mut ZopfliHash)
         where C: Cache,
     {
         let windowstart = instart.saturating_sub(ZOPFLI_WINDOW_SIZE);
@@ -224,7 +369,7 @@ impl Lz77Store {
             return;
         }
 
-        let mut h = ZopfliHash::new();
+        h.reset(s.options.sameruns);
 
         let arr = &in_data[..inend];
         h.warmup(arr, windowstart, inend);
@@ -244,7 +389,7 @@ impl Lz77Store {
             if length >= ZOPFLI_MIN_MATCH as u16 {
                 // Get the distance by recalculating longest match. The found length
                 // should match the length from the path.
-                let longest_match = find_longest_match(s, &mut h, arr, pos, inend, length as usize, &mut None);
+                let longest_match = find_longest_match(s, h, arr, pos, inend, length as usize, &mut None);
                 let dist = longest_match.distance;
                 let dummy_length = longest_match.length;
                 debug_assert!(!(dummy_length != length && length > 2 && dummy_length > 2));
@@ -264,69 +409,144 @@ impl Lz77Store {
         }
     }
 
-    fn get_histogram_at(&self, lpos: usize) -> (Vec<usize>, Vec<usize>) {
+    /// Gets the histogram of lit/len and dist symbols of the items before `index`,
+    /// from the nearest cumulative histogram rather than counting from the start.
+    pub fn histogram_at(&self, index: usize) -> (Vec<usize>, Vec<usize>) {
         let mut ll = vec![0; ZOPFLI_NUM_LL];
         let mut d = vec![0; ZOPFLI_NUM_D];
+        self.histogram_at_into(index, &mut ll, &mut d);
+        (ll, d)
+    }
+
+    /// Same as `histogram_at`, but overwrites `ll` and `d` instead of allocating.
+    fn histogram_at_into(&self, index: usize, ll: &mut [usize], d: &mut [usize]) {
+        assert!(index <= self.size());
+        if index == 0 {
+            for item in ll.iter_mut().chain(d.iter_mut()) {
+                *item = 0;
+            }
+            return;
+        }
+        let lpos = index - 1;
 
         /* The real histogram is created by using the histogram for this chunk, but
         all superfluous values of this chunk subtracted. */
         let llpos = ZOPFLI_NUM_LL * (lpos / ZOPFLI_NUM_LL);
         let dpos = ZOPFLI_NUM_D * (lpos / ZOPFLI_NUM_D);
 
-        for (i, item) in ll.iter_mut().enumerate() {
-            *item = self.ll_counts[llpos + i];
-        }
+        ll.copy_from_slice(&self.ll_counts[llpos..(llpos + ZOPFLI_NUM_LL)]);
         let end = cmp::min(llpos + ZOPFLI_NUM_LL, self.size());
         for i in (lpos + 1)..end {
             ll[self.ll_symbol[i] as usize] -= 1;
         }
 
-        for (i, item) in d.iter_mut().enumerate() {
-            *item = self.d_counts[dpos + i];
-        }
+        d.copy_from_slice(&self.d_counts[dpos..(dpos + ZOPFLI_NUM_D)]);
         let end = cmp::min(dpos + ZOPFLI_NUM_D, self.size());
         for i in (lpos + 1)..end {
             if let LitLen::LengthDist(_, _) = self.litlens[i] {
                  d[self.d_symbol[i] as usize] -= 1;
             }
         }
-
-        (ll, d)
     }
 
     /// Gets the histogram of lit/len and dist symbols in the given range, using the
     /// cumulative histograms, so faster than adding one by one for large range. Does
     /// not add the one end symbol of value 256.
     pub fn get_histogram(&self, lstart: usize, lend: usize) -> (Vec<usize>, Vec<usize>) {
+        let mut ll_counts = vec![0; ZOPFLI_NUM_LL];
+        let mut d_counts = vec![0; ZOPFLI_NUM_D];
+        self.histogram_into(lstart, lend, &mut ll_counts, &mut d_counts);
+        (ll_counts, d_counts)
+    }
+
+    /// Same as `get_histogram`, but overwrites `ll_counts` and `d_counts`, of
+    /// `ZOPFLI_NUM_LL` and `ZOPFLI_NUM_D` items, instead of allocating.
+    pub fn histogram_into(&self, lstart: usize, lend: usize, ll_counts: &mut [usize], d_counts: &mut [usize]) {
         if lstart + ZOPFLI_NUM_LL * 3 > lend {
-            let mut ll_counts = vec![0; ZOPFLI_NUM_LL];
-            let mut d_counts = vec![0; ZOPFLI_NUM_D];
+            for item in ll_counts.iter_mut().chain(d_counts.iter_mut()) {
+                *item = 0;
+            }
             for i in lstart..lend  {
                 ll_counts[self.ll_symbol[i] as usize] += 1;
                 if let LitLen::LengthDist(_, _) = self.litlens[i] {
                     d_counts[self.d_symbol[i] as usize] += 1;
                 }
             }
-            (ll_counts, d_counts)
         } else {
             /* Subtract the cumulative histograms at the end and the start to get the
             histogram for this range. */
-            let (ll, d) = self.get_histogram_at(lend - 1);
+            self.histogram_at_into(lend, ll_counts, d_counts);
 
             if lstart > 0 {
-                let (ll2, d2) = self.get_histogram_at(lstart - 1);
-
-                (
-                    ll.iter().zip(ll2.iter()).map(|(&ll_item1, &ll_item2)|
-                        ll_item1 - ll_item2
-                    ).collect(),
-                    d.iter().zip(d2.iter()).map(|(&d_item1, &d_item2)|
-                        d_item1 - d_item2
-                    ).collect(),
-                )
-            } else {
-                (ll, d)
+                let mut ll2 = [0; ZOPFLI_NUM_LL];
+                let mut d2 = [0; ZOPFLI_NUM_D];
+                self.histogram_at_into(lstart, &mut ll2, &mut d2);
+                for (item, &item2) in ll_counts.iter_mut().zip(ll2.iter()) {
+                    *item -= item2;
+                }
+                for (item, &item2) in d_counts.iter_mut().zip(d2.iter()) {
+                    *item -= item2;
+                }
+            }
+        }
+    }
+
+    /// Checks that the items reproduce `data` at their positions and follow
+    /// each other without gaps, returning the first place where they don't.
+    /// Matches may refer back to bytes before the first item, as the window
+    /// before a block does.
+    pub fn verify(&self, data: &[u8]) -> Result<(), VerifyError> {
+        let mut expected_pos = match self.pos.first() {
+            Some(&pos) => pos,
+            None => return Ok(()),
+        };
+        for (index, (&litlen, &pos)) in self.litlens.iter().zip(self.pos.iter()).enumerate() {
+            if pos != expected_pos {
+                return Err(VerifyError::Gap { index, pos });
+            }
+            match litlen {
+                LitLen::Literal(lit) => {
+                    if data.get(pos) != Some(&(lit as u8)) {
+                        return Err(VerifyError::Mismatch { pos });
+                    }
+                },
+                LitLen::LengthDist(len, dist) => {
+                    let dist = dist as usize;
+                    if dist > pos {
+                        return Err(VerifyError::DistanceTooFar { pos, dist: dist as u16 });
+                    }
+                    // Earlier bytes have already been checked, so comparing
+                    // with the data is the same as comparing with the copy.
+                    for i in pos..(pos + len as usize) {
+                        if i >= data.len() || data[i] != data[i - dist] {
+                            return Err(VerifyError::Mismatch { pos: i });
+                        }
+                    }
+                },
             }
+            expected_pos = pos + litlen.size();
+        }
+        Ok(())
+    }
+
+    /// Summarizes the store: its size, how many of the items are literals and
+    /// matches, the bytes they cover and the five most frequent lit/len symbols.
+    pub fn summary(&self) -> Lz77Summary {
+        let (ll_counts, d_counts) = self.histogram_at(self.size());
+        let matches = d_counts.iter().sum::<usize>();
+        let mut top_symbols: Vec<(u16, usize)> = ll_counts.iter().enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(symbol, &count)| (symbol as u16, count))
+            .collect();
+        top_symbols.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        top_symbols.truncate(5);
+
+        Lz77Summary {
+            symbols: self.size(),
+            literals: self.size() - matches,
+            matches,
+            bytes: self.get_byte_range(0, self.size()),
+            top_symbols,
         }
     }
 
@@ -340,6 +560,83 @@ impl Lz77Store {
     }
 }
 
+/// Adds the symbols from `start` on to the cumulative histograms in `counts`,
+/// which hold one histogram of `num` symbols for every chunk of `num` items,
+/// counting everything up to the end of that chunk. `uncounted` gives how many
+/// of the items in a range were counted as symbol 0 but shouldn't be.
+fn add_cumulative_counts<F>(counts: &mut Vec<usize>, num: usize, symbols: &[u16], start: usize, uncounted: F)
+    where F: Fn(::std::ops::Range<usize>) -> usize
+{
+    let mut i = start;
+    while i < symbols.len() {
+        let chunk_start = num * (i / num);
+        if i == chunk_start {
+            // A new chunk starts from the totals of the previous one.
+            if i == 0 {
+                counts.resize(num, 0);
+            } else {
+                counts.extend_from_within((chunk_start - num)..chunk_start);
+            }
+        }
+        let chunk_end = cmp::min(chunk_start + num, symbols.len());
+        let histogram = &mut counts[chunk_start..(chunk_start + num)];
+
+        let mut quads = symbols[i..chunk_end].chunks_exact(4);
+        for quad in &mut quads {
+            histogram[quad[0] as usize] += 1;
+            histogram[quad[1] as usize] += 1;
+            histogram[quad[2] as usize] += 1;
+            histogram[quad[3] as usize] += 1;
+        }
+        for &symbol in quads.remainder() {
+            histogram[symbol as usize] += 1;
+        }
+        histogram[0] -= uncounted(i..chunk_end);
+
+        i = chunk_end;
+    }
+}
+
+impl fmt::Debug for Lz77Store {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if f.alternate() {
+            f.debug_struct("Lz77Store")
+                .field("litlens", &self.litlens)
+                .field("pos", &self.pos)
+                .field("ll_symbol", &self.ll_symbol)
+                .field("d_symbol", &self.d_symbol)
+                .field("ll_counts", &self.ll_counts)
+                .field("d_counts", &self.d_counts)
+                .finish()
+        } else {
+            write!(f, "Lz77Store {{ {} }}", self.summary())
+        }
+    }
+}
+
+/// An overview of the contents of an `Lz77Store`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lz77Summary {
+    pub symbols: usize,
+    pub literals: usize,
+    pub matches: usize,
+    /* The number of input bytes the store covers. */
+    pub bytes: usize,
+    /* The most frequent lit/len symbols and their counts, most frequent first. */
+    pub top_symbols: Vec<(u16, usize)>,
+}
+
+impl fmt::Display for Lz77Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} symbols ({} literals, {} matches) for {} bytes, most frequent lit/len symbols:",
+               self.symbols, self.literals, self.matches, self.bytes)?;
+        for &(symbol, count) in &self.top_symbols {
+            write!(f, " {}x{}", symbol, count)?;
+        }
+        Ok(())
+    }
+}
+
 /// Some state information for compressing a block.
 /// This is currently a bit under-used (with mainly only the longest match cache),
 /// but is kept for easy future expansion.
@@ -361,6 +658,28 @@ impl<'a> ZopfliBlockState<'a, ZopfliLongestMatchCache> {
             lmc: ZopfliLongestMatchCache::new(blockend - blockstart),
         }
     }
+
+    /// Like `new`, but reuses `lmc`, which is reset for the block first.
+    pub fn with_cache(options: &'a Options, blockstart: usize, blockend: usize, mut lmc: ZopfliLongestMatchCache) -> Self {
+        lmc.reset(blockend - blockstart);
+        ZopfliBlockState {
+            options,
+            blockstart,
+            blockend,
+            lmc,
+        }
+    }
+
+    /// Returns the longest match cache, to be reused with `with_cache`.
+    pub fn into_cache(self) -> ZopfliLongestMatchCache {
+        self.lmc
+    }
+
+    /// Returns the hit and miss counts of the longest match cache.
+    #[cfg(feature = "cache-stats")]
+    pub fn cache_stats(&self) -> CacheStats {
+        self.lmc.stats()
+    }
 }
 
 impl<'a> ZopfliBlockState<'a, NoCache> {
@@ -465,7 +784,7 @@ pub fn find_longest_match<C>(s: &mut ZopfliBlockState<C>, h: &mut ZopfliHash, ar
         limit = size - pos;
     }
 
-    let (bestdist, bestlength) = find_longest_match_loop(h, array, pos, size, limit, sublen);
+    let (bestdist, bestlength) = find_longest_match_loop(h, array, pos, size, limit, s.options.max_distance, sublen);
 
     s.store_in_longest_match_cache(pos, limit, sublen, bestdist as u16, bestlength as u16);
 
@@ -479,7 +798,7 @@ pub fn find_longest_match<C>(s: &mut ZopfliBlockState<C>, h: &mut ZopfliHash, ar
     longest_match
 }
 
-fn find_longest_match_loop(h: &mut ZopfliHash, array: &[u8], pos: usize, size: usize, limit: usize, sublen: &mut Option<&mut [u16]>) -> (i32, usize) {
+fn find_longest_match_loop(h: &mut ZopfliHash, array: &[u8], pos: usize, size: usize, limit: usize, max_distance: usize, sublen: &mut Option<&mut [u16]>) -> (i32, usize) {
     let mut which_hash = Which::Hash1;
     let mut pp = h.head_at(h.val(which_hash) as usize, which_hash);  /* During the whole loop, p == hprev[pp]. */
     let mut p = h.prev_at(pp as usize, which_hash);
@@ -500,8 +819,8 @@ fn find_longest_match_loop(h: &mut ZopfliHash, array: &[u8], pos: usize, size: u
     let mut scan_offset;
     let mut match_offset;
 
-    /* Go through all distances. */
-    while (dist as usize) < ZOPFLI_WINDOW_SIZE {
+    /* Go through all distances, which only grow along the chain. */
+    while (dist as usize) < ZOPFLI_WINDOW_SIZE && (dist as usize) <= max_distance {
         let mut currentlength = 0;
 
         debug_assert!((p as usize) < ZOPFLI_WINDOW_SIZE);
@@ -609,3 +928,375 @@ fn verify_len_dist(data: &[u8], pos: usize, dist: u16, length: u16) {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use util::pseudo_random_bytes;
+    use super::*;
+
+    /// Returns a hash that has been updated for every position up to and
+    /// including `pos`, as the parsers do before looking for a match.
+    fn hash_up_to(data: &[u8], pos: usize) -> ZopfliHash {
+        let mut h = ZopfliHash::new();
+        h.warmup(data, 0, data.len());
+        for i in 0..(pos + 1) {
+            h.update(data, i);
+        }
+        h
+    }
+
+    /// Rebuilds the bytes described by the store, which must start at position 0.
+    fn decode(store: &Lz77Store) -> Vec<u8> {
+        let mut out = vec![];
+        for (&litlen, &pos) in store.litlens.iter().zip(store.pos.iter()) {
+            assert_eq!(pos, out.len());
+            match litlen {
+                LitLen::Literal(lit) => out.push(lit as u8),
+                LitLen::LengthDist(len, dist) => {
+                    for _ in 0..len {
+                        let byte = out[out.len() - dist as usize];
+                        out.push(byte);
+                    }
+                },
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn verify_accepts_correct_store() {
+        let options = Options::default();
+        let data = b"Blah blah blah blah blah! Blah blah blah blah blah!";
+        let mut store = Lz77Store::new();
+        let mut s = ZopfliBlockState::new(&options, 10, data.len());
+        store.greedy(&mut s, data, 10, data.len());
+
+        assert_eq!(store.verify(data), Ok(()));
+        assert_eq!(Lz77Store::new().verify(data), Ok(()));
+    }
+
+    #[test]
+    fn verify_reports_first_difference() {
+        let data = b"abcabcabcX";
+        let items = [
+            (LitLen::Literal(b'a' as u16), 0),
+            (LitLen::Literal(b'b' as u16), 1),
+            (LitLen::Literal(b'c' as u16), 2),
+            (LitLen::LengthDist(7, 3), 3),
+        ];
+        let store = |items: &[(LitLen, usize)]| {
+            let mut store = Lz77Store::new();
+            for &(litlen, pos) in items {
+                store.append_store_item(litlen, pos);
+            }
+            store
+        };
+
+        // The match copies "abcabca" where the data has "abcabcX".
+        assert_eq!(store(&items).verify(data), Err(VerifyError::Mismatch { pos: 9 }));
+        assert_eq!(store(&items[..3]).verify(data), Ok(()));
+        assert_eq!(store(&[items[0], items[2]]).verify(data), Err(VerifyError::Gap { index: 1, pos: 2 }));
+        assert_eq!(store(&[items[0], (LitLen::LengthDist(3, 2), 1)]).verify(data), Err(VerifyError::DistanceTooFar { pos: 1, dist: 2 }));
+        assert_eq!(store(&[items[0], (LitLen::Literal(b'z' as u16), 1)]).verify(data), Err(VerifyError::Mismatch { pos: 1 }));
+    }
+
+    #[test]
+    fn summary_counts_literals_and_matches() {
+        let mut store = Lz77Store::new();
+        store.append_store_item(LitLen::Literal(b'a' as u16), 0);
+        store.append_store_item(LitLen::Literal(b'b' as u16), 1);
+        store.append_store_item(LitLen::LengthDist(4, 2), 2);
+        store.append_store_item(LitLen::Literal(b'a' as u16), 6);
+        store.append_store_item(LitLen::LengthDist(4, 7), 7);
+
+        let summary = store.summary();
+        assert_eq!(summary, Lz77Summary {
+            symbols: 5,
+            literals: 3,
+            matches: 2,
+            bytes: 11,
+            top_symbols: vec![(b'a' as u16, 2), (get_length_symbol(4) as u16, 2), (b'b' as u16, 1)],
+        });
+        assert_eq!(format!("{:?}", store), format!("Lz77Store {{ {} }}", summary));
+        assert!(format!("{:#?}", store).contains("ll_counts"));
+    }
+
+    #[test]
+    fn greedy_parse_reconstructs_input() {
+        let options = Options::default();
+        let data = b"Blah blah blah blah blah! Blah blah blah blah blah!";
+
+        let mut store = Lz77Store::new();
+        let mut s = ZopfliBlockState::new(&options, 0, data.len());
+        store.greedy(&mut s, data, 0, data.len());
+
+        assert_eq!(decode(&store), data.to_vec());
+        assert!(store.size() < data.len());
+        assert!(store.litlens.iter().any(|&litlen| match litlen {
+            LitLen::LengthDist(_, dist) => dist == 5,
+            LitLen::Literal(_) => false,
+        }));
+    }
+
+    #[test]
+    fn lazy_matching_defers_to_longer_match() {
+        // At "abcdefghij" the longest match is "abcd", but "bcdefghij" follows.
+        let data = b"xabcd, bcdefghij; abcdefghij.";
+        let parse = |lazy_matching| {
+            let options = ::OptionsBuilder::new().lazy_matching(lazy_matching).build();
+            let mut store = Lz77Store::new();
+            let mut s = ZopfliBlockState::new(&options, 0, data.len());
+            store.greedy(&mut s, data, 0, data.len());
+            assert_eq!(decode(&store), data.to_vec());
+            store
+        };
+        let matches = |store: &Lz77Store| store.litlens.iter().filter(|litlen| match **litlen {
+            LitLen::LengthDist(..) => true,
+            LitLen::Literal(_) => false,
+        }).count();
+
+        let lazy = parse(true);
+        let eager = parse(false);
+        assert_eq!(&lazy.litlens[16..18], &[LitLen::Literal(b'a' as u16), LitLen::LengthDist(9, 12)]);
+        assert_eq!(&eager.litlens[16..18], &[LitLen::LengthDist(4, 17), LitLen::LengthDist(6, 12)]);
+        assert!(matches(&lazy) < matches(&eager));
+    }
+
+    #[test]
+    fn size_tracks_appended_items() {
+        let mut store = Lz77Store::new();
+        assert!(store.is_empty());
+        assert_eq!(store.size(), 0);
+
+        store.append_store_item(LitLen::Literal(b'a' as u16), 0);
+        store.append_store_item(LitLen::LengthDist(4, 1), 1);
+        store.append_store_item(LitLen::Literal(b'b' as u16), 5);
+        assert!(!store.is_empty());
+        assert_eq!(store.size(), 3);
+        assert_eq!(store.ll_symbols(), &[b'a' as u16, get_length_symbol(4) as u16, b'b' as u16]);
+        assert_eq!(store.d_symbols(), &[0, get_dist_symbol(1) as u16, 0]);
+
+        store.reset();
+        assert!(store.is_empty());
+    }
+
+    fn random_store(size: usize, seed: u32) -> Lz77Store {
+        let mut store = Lz77Store::new();
+        let mut pos = 0;
+        for item in pseudo_random_bytes(4 * size, seed).chunks(4) {
+            let litlen = if item[0] % 3 == 1 {
+                LitLen::LengthDist(3 + item[1] as u16, 1 + (item[2] as u16 | (item[3] as u16) << 8) % 32768)
+            } else {
+                LitLen::Literal(item[1] as u16)
+            };
+            store.append_store_item(litlen, pos);
+            pos += litlen.size();
+        }
+        store
+    }
+
+    #[test]
+    fn batched_histograms_match_incremental() {
+        let parts = [random_store(1000, 1), random_store(5, 2), random_store(3000, 3), random_store(288, 4)];
+
+        let mut batched = Lz77Store::new();
+        let mut incremental = Lz77Store::new();
+        for part in &parts {
+            batched.append_store(part);
+            for (&litlen, &pos) in part.litlens.iter().zip(part.pos.iter()) {
+                incremental.append_store_item(litlen, pos);
+            }
+            assert_eq!(batched.ll_counts, incremental.ll_counts);
+            assert_eq!(batched.d_counts, incremental.d_counts);
+        }
+        assert_eq!(batched.litlens, incremental.litlens);
+        assert_eq!(batched.ll_symbol, incremental.ll_symbol);
+        assert_eq!(batched.d_symbol, incremental.d_symbol);
+    }
+
+    #[test]
+    fn append_range_rebases_positions() {
+        let mut store = random_store(100, 7);
+        let other = random_store(2000, 8);
+        let mut expected = random_store(100, 7);
+        let mut pos = expected.pos[99] + expected.litlens[99].size();
+        for &litlen in &other.litlens[500..1300] {
+            expected.append_store_item(litlen, pos);
+            pos += litlen.size();
+        }
+
+        store.append_range(&other, 500, 1300);
+        store.append_range(&other, 1300, 1300);
+        assert_eq!(store.iter().collect::<Vec<_>>(), expected.iter().collect::<Vec<_>>());
+        for &index in &[0, 100, 101, 288, 577, 900] {
+            assert_eq!(store.histogram_at(index), expected.histogram_at(index), "index {}", index);
+        }
+
+        let mut empty = Lz77Store::new();
+        empty.append_range(&other, 10, 20);
+        assert_eq!(empty.pos[0], 0);
+        assert_eq!(empty.litlens[..], other.litlens[10..20]);
+    }
+
+    #[test]
+    fn histogram_at_matches_counting_from_start() {
+        let store = random_store(2000, 5);
+        for &index in &[0, 1, 29, 30, 31, 287, 288, 289, 1000, 1999, 2000] {
+            let mut ll = vec![0; ZOPFLI_NUM_LL];
+            let mut d = vec![0; ZOPFLI_NUM_D];
+            for i in 0..index {
+                ll[store.ll_symbol[i] as usize] += 1;
+                if let LitLen::LengthDist(..) = store.litlens[i] {
+                    d[store.d_symbol[i] as usize] += 1;
+                }
+            }
+            assert_eq!(store.histogram_at(index), (ll, d), "index {}", index);
+        }
+    }
+
+    #[test]
+    fn iter_yields_the_appended_commands() {
+        let commands = [(b'a' as u16, 0, 0), (b'b' as u16, 0, 1), (5, 2, 2), (258, 1, 7), (b'c' as u16, 0, 265)];
+        let mut store = Lz77Store::new();
+        for &(litlen, dist, pos) in &commands {
+            store.lit_len_dist(litlen, dist, pos);
+        }
+        let expected: Vec<Lz77Command> = commands.iter()
+            .map(|&(litlen, dist, pos)| Lz77Command { litlen, dist, pos })
+            .collect();
+        assert_eq!(store.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(Lz77Store::new().iter().count(), 0);
+    }
+
+    #[test]
+    fn histogram_into_overwrites_with_range_counts() {
+        let store = random_store(2000, 6);
+        // Stale counts from an earlier range must not leak into the next.
+        let mut ll = [7; ZOPFLI_NUM_LL];
+        let mut d = [7; ZOPFLI_NUM_D];
+        for &(lstart, lend) in &[(0, 2000), (0, 10), (5, 900), (289, 1500), (1000, 1000), (1999, 2000)] {
+            store.histogram_into(lstart, lend, &mut ll, &mut d);
+            let mut expected_ll = vec![0; ZOPFLI_NUM_LL];
+            let mut expected_d = vec![0; ZOPFLI_NUM_D];
+            for i in lstart..lend {
+                expected_ll[store.ll_symbol[i] as usize] += 1;
+                if let LitLen::LengthDist(..) = store.litlens[i] {
+                    expected_d[store.d_symbol[i] as usize] += 1;
+                }
+            }
+            assert_eq!((ll.to_vec(), d.to_vec()), (expected_ll, expected_d), "range {}..{}", lstart, lend);
+        }
+    }
+
+    #[test]
+    fn with_capacity_does_not_reallocate() {
+        let n = 3 * ZOPFLI_NUM_LL + 5;
+        let mut store = Lz77Store::with_capacity(n);
+        let capacities = |store: &Lz77Store| {
+            [store.litlens.capacity(), store.pos.capacity(),
+             store.ll_symbol.capacity(), store.d_symbol.capacity(),
+             store.ll_counts.capacity(), store.d_counts.capacity()]
+        };
+        let before = capacities(&store);
+        for i in 0..n {
+            let litlen = if i % 3 == 0 { LitLen::LengthDist(10, 1000) } else { LitLen::Literal(i as u16 % 256) };
+            store.append_store_item(litlen, i);
+        }
+        assert_eq!(store.size(), n);
+        assert_eq!(capacities(&store), before);
+    }
+
+    #[test]
+    fn finds_longest_match_and_fills_cache() {
+        let options = Options::default();
+        let mut data = b"abcdefgh_abcdefgh_X".to_vec();
+        // Filler that can't extend the match, long enough to allow a full
+        // ZOPFLI_MAX_MATCH limit (the cache is only filled in that case).
+        data.extend((0..300).map(|i| (i % 251) as u8));
+
+        let mut s = ZopfliBlockState::new(&options, 0, data.len());
+        let mut h = hash_up_to(&data, 9);
+        let mut sublen = vec![0; ZOPFLI_MAX_MATCH + 1];
+
+        let longest_match = find_longest_match(&mut s, &mut h, &data, 9, data.len(), ZOPFLI_MAX_MATCH, &mut Some(&mut sublen));
+        assert_eq!((longest_match.distance, longest_match.length), (9, 9));
+        assert!(!longest_match.from_cache);
+        assert!(sublen[3..10].iter().all(|&d| d == 9));
+
+        let cached = s.try_get_from_longest_match_cache(9, ZOPFLI_MAX_MATCH, &mut None);
+        assert!(cached.from_cache);
+        assert_eq!((cached.distance, cached.length), (9, 9));
+
+        let mut cached_sublen = vec![0; ZOPFLI_MAX_MATCH + 1];
+        let cached = find_longest_match(&mut s, &mut h, &data, 9, data.len(), ZOPFLI_MAX_MATCH, &mut Some(&mut cached_sublen));
+        assert!(cached.from_cache);
+        assert_eq!(&cached_sublen[3..10], &sublen[3..10]);
+    }
+
+    #[test]
+    #[cfg(feature = "cache-stats")]
+    fn repeated_queries_hit_the_cache() {
+        let options = Options::default();
+        let data: Vec<u8> = b"0123456789".iter().cycle().take(1000).cloned().collect();
+        let mut s = ZopfliBlockState::new(&options, 0, data.len());
+        let mut h = hash_up_to(&data, 500);
+        let mut sublen = vec![0; ZOPFLI_MAX_MATCH + 1];
+
+        find_longest_match(&mut s, &mut h, &data, 500, data.len(), ZOPFLI_MAX_MATCH, &mut Some(&mut sublen));
+        assert_eq!(s.cache_stats(), CacheStats { hits: 0, misses: 1 });
+        for _ in 0..3 {
+            find_longest_match(&mut s, &mut h, &data, 500, data.len(), ZOPFLI_MAX_MATCH, &mut Some(&mut sublen));
+        }
+        assert_eq!(s.cache_stats(), CacheStats { hits: 3, misses: 1 });
+    }
+
+    #[test]
+    fn finds_longest_match_in_run_of_same_byte() {
+        let options = Options::default();
+        let data = vec![b'a'; 1000];
+
+        let mut s = ZopfliBlockState::new_without_cache(&options, 0, data.len());
+        let mut h = hash_up_to(&data, 1);
+
+        let longest_match = find_longest_match(&mut s, &mut h, &data, 1, data.len(), ZOPFLI_MAX_MATCH, &mut None);
+        assert_eq!(longest_match.distance, 1);
+        assert_eq!(longest_match.length as usize, ZOPFLI_MAX_MATCH);
+    }
+
+    #[test]
+    fn sameruns_doesnt_change_matches_in_long_run() {
+        let data = vec![b'z'; 10000];
+        let mut stores = vec![];
+        for &sameruns in &[true, false] {
+            let options = ::OptionsBuilder::new().sameruns(sameruns).build();
+            let mut s = ZopfliBlockState::new_without_cache(&options, 0, data.len());
+            let mut h = ZopfliHash::new();
+            h.reset(sameruns);
+            h.warmup(&data, 0, data.len());
+            for i in 0..5001 {
+                h.update(&data, i);
+            }
+            let longest_match = find_longest_match(&mut s, &mut h, &data, 5000, data.len(), ZOPFLI_MAX_MATCH, &mut None);
+            assert_eq!(longest_match.distance, 1);
+            assert_eq!(longest_match.length as usize, ZOPFLI_MAX_MATCH);
+
+            let mut store = Lz77Store::new();
+            store.greedy(&mut s, &data, 0, data.len());
+            assert_eq!(decode(&store), data);
+            stores.push(store.litlens);
+        }
+        assert_eq!(stores[0], stores[1]);
+    }
+
+    #[test]
+    fn no_match_without_earlier_occurrence() {
+        let options = Options::default();
+        let data: Vec<u8> = (0..100).collect();
+
+        let mut s = ZopfliBlockState::new_without_cache(&options, 0, data.len());
+        let mut h = hash_up_to(&data, 50);
+
+        let longest_match = find_longest_match(&mut s, &mut h, &data, 50, data.len(), ZOPFLI_MAX_MATCH, &mut None);
+        assert!((longest_match.length as usize) < ZOPFLI_MIN_MATCH);
+    }
+}
@@ -12,11 +12,7 @@ fn main() {
     // TODO: CLI arguments
     // TODO: Allow specifying output to STDOUT
 
-    let extension = match output_type {
-        zopfli::Format::Gzip => ".gz",
-        zopfli::Format::Zlib => ".zlib",
-        zopfli::Format::Deflate => ".deflate",
-    };
+    let extension = output_type.extension();
 
     for filename in env::args().skip(1) {
         let mut file = File::open(&filename)
@@ -28,7 +24,7 @@ fn main() {
         file.read_to_end(&mut data)
             .unwrap_or_else(|why| panic!("couldn't read {}: {}", filename, why));
 
-        let out_filename = format!("{}{}", filename, extension);
+        let out_filename = format!("{}.{}", filename, extension);
 
         // Attempt to create the output file, panic if the output file could not be opened
         let out_file = File::create(&out_filename)
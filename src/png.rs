@@ -0,0 +1,198 @@
+//! Recompression of PNG image data, in the spirit of ZopfliPNG.
+//!
+//! PNG compresses its scanlines with zlib after running each of them through
+//! one of five filters. Which filters compress best depends on the image, so
+//! `recompress_idat` tries several strategies for choosing them and keeps the
+//! smallest result.
+
+use {compress_to_vec, Format, Options};
+
+/// The PNG filter types, in the order of their type byte.
+const NONE: u8 = 0;
+const SUB: u8 = 1;
+const UP: u8 = 2;
+const AVERAGE: u8 = 3;
+const PAETH: u8 = 4;
+
+/// How the filter of each scanline is chosen. All variants but `MinSum` use
+/// the filter of the same name for every scanline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterStrategy {
+    None,
+    Sub,
+    Up,
+    Average,
+    Paeth,
+    /// For each scanline, the filter whose output has the smallest sum of
+    /// absolute values when read as signed bytes (the heuristic libpng uses).
+    MinSum,
+}
+
+impl FilterStrategy {
+    /// All strategies, in the order `recompress_idat` tries them.
+    pub const ALL: [FilterStrategy; 6] = [
+        FilterStrategy::None,
+        FilterStrategy::Sub,
+        FilterStrategy::Up,
+        FilterStrategy::Average,
+        FilterStrategy::Paeth,
+        FilterStrategy::MinSum,
+    ];
+}
+
+/// Recompresses the image data of a PNG. `raw_scanlines` holds the `height`
+/// unfiltered scanlines of `width * bytes_per_pixel` bytes each, without filter
+/// type bytes. Every `FilterStrategy` is tried and the smallest zlib stream,
+/// ready to be stored in IDAT chunks, is returned.
+///
+/// For images with fewer than 8 bits per pixel, pass 1 as `bytes_per_pixel`
+/// and the number of bytes per scanline as `width`.
+pub fn recompress_idat(raw_scanlines: &[u8], width: usize, height: usize, bytes_per_pixel: usize, options: &Options) -> Vec<u8> {
+    assert!(bytes_per_pixel > 0);
+    assert_eq!(raw_scanlines.len(), width * height * bytes_per_pixel);
+
+    FilterStrategy::ALL.iter()
+        .map(|&strategy| {
+            let filtered = filter_scanlines(raw_scanlines, width * bytes_per_pixel, bytes_per_pixel, strategy);
+            compress_to_vec(options, &Format::Zlib, &filtered)
+        })
+        .min_by_key(|compressed| compressed.len())
+        .unwrap()
+}
+
+/// Filters every scanline of `stride` bytes with the filter `strategy` picks,
+/// prefixing each with its filter type byte.
+pub fn filter_scanlines(raw_scanlines: &[u8], stride: usize, bytes_per_pixel: usize, strategy: FilterStrategy) -> Vec<u8> {
+    let height = raw_scanlines.len().checked_div(stride).unwrap_or(0);
+    let mut out = Vec::with_capacity(height * (stride + 1));
+    let zeros = vec![0; stride];
+    let mut candidate = Vec::with_capacity(stride + 1);
+
+    for y in 0..height {
+        let row = &raw_scanlines[(y * stride)..((y + 1) * stride)];
+        let prev = if y == 0 { &zeros[..] } else { &raw_scanlines[((y - 1) * stride)..(y * stride)] };
+
+        let filter_type = match strategy {
+            FilterStrategy::None => NONE,
+            FilterStrategy::Sub => SUB,
+            FilterStrategy::Up => UP,
+            FilterStrategy::Average => AVERAGE,
+            FilterStrategy::Paeth => PAETH,
+            FilterStrategy::MinSum => {
+                (NONE..(PAETH + 1)).min_by_key(|&filter_type| {
+                    candidate.clear();
+                    filter_row(filter_type, row, prev, bytes_per_pixel, &mut candidate);
+                    candidate[1..].iter().map(|&b| (b as i8 as i32).abs()).sum::<i32>()
+                }).unwrap()
+            },
+        };
+        filter_row(filter_type, row, prev, bytes_per_pixel, &mut out);
+    }
+    out
+}
+
+/// Appends the filter type byte and `row` filtered with it to `out`. `prev` is
+/// the previous unfiltered scanline, all zeros for the first one.
+fn filter_row(filter_type: u8, row: &[u8], prev: &[u8], bytes_per_pixel: usize, out: &mut Vec<u8>) {
+    out.push(filter_type);
+    for (i, (&x, &b)) in row.iter().zip(prev.iter()).enumerate() {
+        let a = if i >= bytes_per_pixel { row[i - bytes_per_pixel] } else { 0 };
+        let c = if i >= bytes_per_pixel { prev[i - bytes_per_pixel] } else { 0 };
+        let predicted = match filter_type {
+            NONE => 0,
+            SUB => a,
+            UP => b,
+            AVERAGE => ((a as u16 + b as u16) / 2) as u8,
+            PAETH => paeth_predictor(a, b, c),
+            _ => unreachable!(),
+        };
+        out.push(x.wrapping_sub(predicted));
+    }
+}
+
+/// Predicts a byte from its left (`a`), upper (`b`) and upper left (`c`)
+/// neighbours, as defined by the PNG specification.
+fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use super::*;
+    use OptionsBuilder;
+
+    /// Reverses `filter_scanlines`.
+    fn unfilter(filtered: &[u8], stride: usize, bytes_per_pixel: usize) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::with_capacity(filtered.len());
+        for (y, line) in filtered.chunks(stride + 1).enumerate() {
+            let filter_type = line[0];
+            for (i, &x) in line[1..].iter().enumerate() {
+                let pos = y * stride + i;
+                let a = if i >= bytes_per_pixel { out[pos - bytes_per_pixel] } else { 0 };
+                let b = if y > 0 { out[pos - stride] } else { 0 };
+                let c = if y > 0 && i >= bytes_per_pixel { out[pos - stride - bytes_per_pixel] } else { 0 };
+                let predicted = match filter_type {
+                    NONE => 0,
+                    SUB => a,
+                    UP => b,
+                    AVERAGE => ((a as u16 + b as u16) / 2) as u8,
+                    PAETH => paeth_predictor(a, b, c),
+                    _ => panic!("invalid filter type {}", filter_type),
+                };
+                out.push(x.wrapping_add(predicted));
+            }
+        }
+        out
+    }
+
+    fn gradient(width: usize, height: usize) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                pixels.push((x * 255 / width) as u8);
+                pixels.push((y * 255 / height) as u8);
+                pixels.push(((x + y) * 4) as u8);
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn every_strategy_unfilters_to_the_input() {
+        let pixels = gradient(20, 10);
+        for &strategy in &FilterStrategy::ALL {
+            let filtered = filter_scanlines(&pixels, 60, 3, strategy);
+            assert_eq!(filtered.len(), pixels.len() + 10);
+            assert_eq!(unfilter(&filtered, 60, 3), pixels, "{:?}", strategy);
+        }
+    }
+
+    #[test]
+    fn recompressed_gradient_round_trips() {
+        let (width, height) = (32, 16);
+        let pixels = gradient(width, height);
+        let options = OptionsBuilder::new().numiterations(3).build();
+        let compressed = recompress_idat(&pixels, width, height, 3, &options);
+
+        let mut filtered = vec![];
+        ZlibDecoder::new(&compressed[..]).read_to_end(&mut filtered).unwrap();
+        assert_eq!(unfilter(&filtered, width * 3, 3), pixels);
+
+        let unfiltered = compress_to_vec(&options, &Format::Zlib, &filter_scanlines(&pixels, width * 3, 3, FilterStrategy::None));
+        assert!(compressed.len() <= unfiltered.len());
+    }
+}
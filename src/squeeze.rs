@@ -9,14 +9,35 @@
 
 use std::{cmp, f64, f32};
 
-use cache::Cache;
+use cache::{Cache, ZopfliLongestMatchCache};
 use deflate::{calculate_block_size, BlockType};
 use hash::ZopfliHash;
 use lz77::{Lz77Store, ZopfliBlockState, find_longest_match, LitLen};
+use tree::calculate_entropy;
 use symbols::{get_dist_extra_bits, get_dist_symbol, get_length_extra_bits, get_length_symbol};
 use util::{ZOPFLI_NUM_LL, ZOPFLI_NUM_D, ZOPFLI_WINDOW_SIZE, ZOPFLI_WINDOW_MASK, ZOPFLI_MAX_MATCH};
+use ProgressEvent;
 
-const K_INV_LOG2: f64 = f64::consts::LOG2_E;  // 1.0 / log(2.0)
+/// A cost model gives the cost, in bits, of a literal (`dist == 0`) or of a
+/// length/distance pair, and is what the squeeze runs minimize.
+pub trait CostModel {
+    fn cost(&self, litlen: u32, dist: u32) -> f64;
+}
+
+/// Cost model of the fixed tree, see `get_cost_fixed`.
+pub struct FixedCostModel;
+
+impl CostModel for FixedCostModel {
+    fn cost(&self, litlen: u32, dist: u32) -> f64 {
+        get_cost_fixed(litlen, dist)
+    }
+}
+
+impl CostModel for SymbolStats {
+    fn cost(&self, litlen: u32, dist: u32) -> f64 {
+        get_cost_stat(litlen, dist, self)
+    }
+}
 
 /// Cost model which should exactly match fixed tree.
 fn get_cost_fixed(litlen: u32, dist: u32) -> f64 {
@@ -55,30 +76,46 @@ fn get_cost_stat(litlen: u32, dist: u32, stats: &SymbolStats) -> f64 {
     }
 }
 
-#[derive(Default)]
-struct RanState {
+/// The random number generator the optimal parse perturbs its statistics with
+/// once they stop improving.
+pub struct RanState {
     m_w: u32,
     m_z: u32,
 }
 
+impl Default for RanState {
+    fn default() -> RanState {
+        RanState::new()
+    }
+}
+
 impl RanState {
-    fn new() -> RanState {
+    /// The state zopfli starts each optimal parse from.
+    pub fn new() -> RanState {
+        RanState::from_seed(1, 2)
+    }
+
+    /// A state with the given seed, for reproducible sequences other than the
+    /// one of `new`.
+    pub fn from_seed(m_w: u32, m_z: u32) -> RanState {
         RanState {
-            m_w: 1,
-            m_z: 2,
+            m_w,
+            m_z,
         }
     }
 
     /// Get random number: "Multiply-With-Carry" generator of G. Marsaglia
-    fn random_marsaglia(&mut self) -> u32 {
+    pub fn random_marsaglia(&mut self) -> u32 {
         self.m_z = 36969 * (self.m_z & 65535) + (self.m_z >> 16);
         self.m_w = 18000 * (self.m_w & 65535) + (self.m_w >> 16);
         (self.m_z << 16).wrapping_add(self.m_w) // 32-bit result.
     }
 }
 
+/// The symbol frequencies of a parse and the cost in bits of each symbol that
+/// follows from them, which the optimal parse uses as its cost model.
 #[derive(Copy)]
-struct SymbolStats {
+pub struct SymbolStats {
   /* The literal and length symbols. */
   litlens: [usize; ZOPFLI_NUM_LL],
   /* The 32 unique dist symbols, not the 32768 possible dists. */
@@ -127,54 +164,19 @@ impl SymbolStats {
         self.litlens[256] = 1; // End symbol.
     }
 
-    /// Calculates the entropy of each symbol, based on the counts of each symbol. The
-    /// result is similar to the result of length_limited_code_lengths, but with the
-    /// actual theoritical bit lengths according to the entropy. Since the resulting
-    /// values are fractional, they cannot be used to encode the tree specified by
-    /// DEFLATE.
+    /// Calculates the entropy of each symbol, see `tree::calculate_entropy`.
     fn calculate_entropy(&mut self) {
-        fn calculate_and_store_entropy(count: &[usize], bitlengths: &mut [f64]) {
-            let n = count.len();
-
-            let sum = count.iter().fold(0, |acc, &x| acc + x);
-
-            let log2sum = (if sum == 0 { n } else { sum } as f64).ln() * K_INV_LOG2;
-
-            for i in 0..n {
-                // When the count of the symbol is 0, but its cost is requested anyway, it
-                // means the symbol will appear at least once anyway, so give it the cost as if
-                // its count is 1.
-                if count[i] == 0 {
-                    bitlengths[i] = log2sum;
-                } else {
-                    bitlengths[i] = log2sum - (count[i] as f64).ln() * K_INV_LOG2;
-                }
-
-                // Depending on compiler and architecture, the above subtraction of two
-                // floating point numbers may give a negative result very close to zero
-                // instead of zero (e.g. -5.973954e-17 with gcc 4.1.2 on Ubuntu 11.4). Clamp
-                // it to zero. These floating point imprecisions do not affect the cost model
-                // significantly so this is ok.
-                if bitlengths[i] < 0.0 && bitlengths[i] > -1E-5 {
-                    bitlengths[i] = 0.0;
-                }
-                debug_assert!(bitlengths[i] >= 0.0);
-            }
-        }
-
-        calculate_and_store_entropy(&self.litlens, &mut self.ll_symbols);
-        calculate_and_store_entropy(&self.dists, &mut self.d_symbols);
+        self.ll_symbols.copy_from_slice(&calculate_entropy(&self.litlens));
+        self.d_symbols.copy_from_slice(&calculate_entropy(&self.dists));
     }
 
     /// Appends the symbol statistics from the store.
     fn get_statistics(&mut self, store: &Lz77Store) {
-        for &litlen in &store.litlens {
-            match litlen {
-                LitLen::Literal(lit) => self.litlens[lit as usize] += 1,
-                LitLen::LengthDist(len, dist) => {
-                    self.litlens[get_length_symbol(len as usize) as usize] += 1;
-                    self.dists[get_dist_symbol(dist as i32) as usize] += 1;
-                }
+        let symbols = store.ll_symbols().iter().zip(store.d_symbols());
+        for (&litlen, (&ll_symbol, &d_symbol)) in store.litlens.iter().zip(symbols) {
+            self.litlens[ll_symbol as usize] += 1;
+            if let LitLen::LengthDist(..) = litlen {
+                self.dists[d_symbol as usize] += 1;
             }
         }
         self.litlens[256] = 1;  /* End symbol. */
@@ -186,25 +188,24 @@ impl SymbolStats {
         self.litlens = [0; ZOPFLI_NUM_LL];
         self.dists = [0; ZOPFLI_NUM_D];
     }
-}
-
-fn add_weighed_stat_freqs(stats1: &SymbolStats, w1: f64, stats2: &SymbolStats, w2: f64) -> SymbolStats {
-    let mut result = SymbolStats::default();
 
-    for i in 0..ZOPFLI_NUM_LL {
-        result.litlens[i] = (stats1.litlens[i] as f64 * w1 + stats2.litlens[i] as f64 * w2) as usize;
-    }
-    for i in 0..ZOPFLI_NUM_D {
-        result.dists[i] = (stats1.dists[i] as f64 * w1 + stats2.dists[i] as f64 * w2) as usize;
+    /// Replaces the frequencies with the weighted sum `w1 * self + w2 * other`,
+    /// rounded down. The entropy is not recalculated.
+    fn add_weighted_freqs(&mut self, other: &SymbolStats, w1: f64, w2: f64) {
+        for (freq, &other_freq) in self.litlens.iter_mut().zip(other.litlens.iter()) {
+            *freq = (*freq as f64 * w1 + other_freq as f64 * w2) as usize;
+        }
+        for (freq, &other_freq) in self.dists.iter_mut().zip(other.dists.iter()) {
+            *freq = (*freq as f64 * w1 + other_freq as f64 * w2) as usize;
+        }
+        self.litlens[256] = 1; // End symbol.
     }
-    result.litlens[256] = 1; // End symbol.
-    result
 }
 
 /// Finds the minimum possible cost this cost model can return for valid length and
 /// distance symbols.
-fn get_cost_model_min_cost<F>(costmodel: F) -> f64
-        where F: Fn(u32, u32) -> f64
+fn get_cost_model_min_cost<M>(costmodel: &M) -> f64
+        where M: CostModel + ?Sized
 {
     let mut bestlength = 0; // length that has lowest cost in the cost model
     let mut bestdist = 0; // distance that has lowest cost in the cost model
@@ -221,7 +222,7 @@ fn get_cost_model_min_cost<F>(costmodel: F) -> f64
 
     let mut mincost = f64::MAX;
     for i in 3..259 {
-        let c = costmodel(i, 1);
+        let c = costmodel.cost(i, 1);
         if c < mincost {
             bestlength = i as i32;
             mincost = c;
@@ -230,13 +231,13 @@ fn get_cost_model_min_cost<F>(costmodel: F) -> f64
 
     mincost = f64::MAX;
     for &dsym in dsymbols.iter().take(30) {
-        let c = costmodel(3, dsym as u32);
+        let c = costmodel.cost(3, dsym as u32);
         if c < mincost {
             bestdist = dsym;
             mincost = c;
         }
     }
-    costmodel(bestlength as u32, bestdist as u32)
+    costmodel.cost(bestlength as u32, bestdist as u32)
 }
 
 /// Performs the forward pass for "squeeze". Gets the most optimal length to reach
@@ -245,12 +246,12 @@ fn get_cost_model_min_cost<F>(costmodel: F) -> f64
 /// `in_data`: the input data array
 /// `instart`: where to start
 /// `inend`: where to stop (not inclusive)
-/// `costmodel`: function to calculate the cost of some lit/len/dist pair.
+/// `costmodel`: model to calculate the cost of some lit/len/dist pair.
 /// `length_array`: output array of size `(inend - instart)` which will receive the best
 ///     length to reach this byte from a previous byte.
 /// returns the cost that was, according to the `costmodel`, needed to get to the end.
-fn get_best_lengths<F, C>(s: &mut ZopfliBlockState<C>, in_data: &[u8], instart: usize, inend: usize, costmodel: F, h: &mut ZopfliHash, costs: &mut Vec<f32>) -> (f64, Vec<u16>)
-    where F: Fn(u32, u32) -> f64,
+fn get_best_lengths<M, C>(s: &mut ZopfliBlockState<C>, in_data: &[u8], instart: usize, inend: usize, costmodel: &M, h: &mut ZopfliHash, costs: &mut Vec<f32>) -> (f64, Vec<u16>)
+    where M: CostModel + ?Sized,
           C: Cache,
 {
     // Best cost to get here so far.
@@ -261,7 +262,7 @@ fn get_best_lengths<F, C>(s: &mut ZopfliBlockState<C>, in_data: &[u8], instart:
     }
     let windowstart = instart.saturating_sub(ZOPFLI_WINDOW_SIZE);
 
-    h.reset();
+    h.reset(s.options.sameruns);
     let arr = &in_data[..inend];
     h.warmup(arr, windowstart, inend);
     for i in windowstart..instart {
@@ -280,38 +281,39 @@ fn get_best_lengths<F, C>(s: &mut ZopfliBlockState<C>, in_data: &[u8], instart:
     let mut leng;
     let mut longest_match;
     let mut sublen = vec![0; ZOPFLI_MAX_MATCH + 1];
-    let mincost = get_cost_model_min_cost(&costmodel);
+    let mincost = get_cost_model_min_cost(costmodel);
+    let max_match = s.options.max_match;
     while i < inend {
         let mut j = i - instart;  // Index in the costs array and length_array.
         h.update(arr, i);
 
         // If we're in a long repetition of the same character and have more than
-        // ZOPFLI_MAX_MATCH characters before and after our position.
-        if h.same[i & ZOPFLI_WINDOW_MASK] > ZOPFLI_MAX_MATCH as u16 * 2
-            && i > instart + ZOPFLI_MAX_MATCH + 1
-            && i + ZOPFLI_MAX_MATCH * 2 + 1 < inend
-            && h.same[(i - ZOPFLI_MAX_MATCH) & ZOPFLI_WINDOW_MASK] > ZOPFLI_MAX_MATCH as u16 {
-
-            let symbolcost = costmodel(ZOPFLI_MAX_MATCH as u32, 1);
-            // Set the length to reach each one to ZOPFLI_MAX_MATCH, and the cost to
+        // max_match characters before and after our position.
+        if h.same[i & ZOPFLI_WINDOW_MASK] as usize > max_match * 2
+            && i > instart + max_match + 1
+            && i + max_match * 2 + 1 < inend
+            && h.same[(i - max_match) & ZOPFLI_WINDOW_MASK] as usize > max_match {
+
+            let symbolcost = costmodel.cost(max_match as u32, 1);
+            // Set the length to reach each one to max_match, and the cost to
             // the cost corresponding to that length. Doing this, we skip
-            // ZOPFLI_MAX_MATCH values to avoid calling ZopfliFindLongestMatch.
+            // max_match values to avoid calling ZopfliFindLongestMatch.
 
-            for _ in 0..ZOPFLI_MAX_MATCH {
-                costs[j + ZOPFLI_MAX_MATCH] = costs[j] + symbolcost as f32;
-                length_array[j + ZOPFLI_MAX_MATCH] = ZOPFLI_MAX_MATCH as u16;
+            for _ in 0..max_match {
+                costs[j + max_match] = costs[j] + symbolcost as f32;
+                length_array[j + max_match] = max_match as u16;
                 i += 1;
                 j += 1;
                 h.update(arr, i);
             }
         }
 
-        longest_match = find_longest_match(s, h, arr, i, inend, ZOPFLI_MAX_MATCH, &mut Some(&mut sublen));
+        longest_match = find_longest_match(s, h, arr, i, inend, max_match, &mut Some(&mut sublen));
         leng = longest_match.length;
 
         // Literal.
         if i + 1 <= inend {
-            let new_cost = costmodel(arr[i] as u32, 0) + costs[j] as f64;
+            let new_cost = costmodel.cost(arr[i] as u32, 0) + costs[j] as f64;
             debug_assert!(new_cost >= 0.0);
             if new_cost < costs[j + 1] as f64 {
                 costs[j + 1] = new_cost as f32;
@@ -329,7 +331,7 @@ fn get_best_lengths<F, C>(s: &mut ZopfliBlockState<C>, in_data: &[u8], instart:
                 continue;
             }
 
-            let new_cost = costmodel(k as u32, sublength as u32) + costs[j] as f64;
+            let new_cost = costmodel.cost(k as u32, sublength as u32) + costs[j] as f64;
             debug_assert!(new_cost >= 0.0);
             if new_cost < costs[j + k] as f64 {
                 debug_assert!(k <= ZOPFLI_MAX_MATCH);
@@ -377,17 +379,17 @@ fn trace_backwards(size: usize, length_array: &[u16]) -> Vec<u16> {
 /// `instart`: where to start
 /// `inend`: where to stop (not inclusive)
 /// `length_array`: array of size `(inend - instart)` used to store lengths
-/// `costmodel`: function to use as the cost model for this squeeze run
+/// `costmodel`: the cost model to use for this squeeze run
 /// `store`: place to output the LZ77 data
 /// returns the cost that was, according to the `costmodel`, needed to get to the end.
 ///     This is not the actual cost.
-fn lz77_optimal_run<F, C>(s: &mut ZopfliBlockState<C>, in_data: &[u8], instart: usize, inend: usize, costmodel: F, store: &mut Lz77Store, h: &mut ZopfliHash, costs: &mut Vec<f32>)
-    where F: Fn(u32, u32) -> f64,
+fn lz77_optimal_run<M, C>(s: &mut ZopfliBlockState<C>, in_data: &[u8], instart: usize, inend: usize, costmodel: &M, store: &mut Lz77Store, scratch: &mut Scratch)
+    where M: CostModel + ?Sized,
           C: Cache,
 {
-    let (cost, length_array) = get_best_lengths(s, in_data, instart, inend, costmodel, h, costs);
+    let (cost, length_array) = get_best_lengths(s, in_data, instart, inend, costmodel, &mut scratch.hash, &mut scratch.costs);
     let path = trace_backwards(inend - instart, &length_array);
-    store.follow_path(in_data, instart, inend, path, s);
+    store.follow_path(in_data, instart, inend, path, s, &mut scratch.hash);
     debug_assert!(cost < f64::MAX);
 }
 
@@ -405,28 +407,74 @@ pub fn lz77_optimal_fixed<C>(s: &mut ZopfliBlockState<C>, in_data: &[u8], instar
 {
     s.blockstart = instart;
     s.blockend = inend;
-    let mut h = ZopfliHash::new();
-    let mut costs = Vec::with_capacity(inend - instart - 1);
-    lz77_optimal_run(s, in_data, instart, inend, get_cost_fixed, store, &mut h, &mut costs);
+    let mut scratch = Scratch::new();
+    scratch.costs.reserve(inend - instart + 1);
+    lz77_optimal_run(s, in_data, instart, inend, &FixedCostModel, store, &mut scratch);
+}
+
+/// The buffers the optimal parse of a block needs, which can be kept to be
+/// reused for the next block instead of allocating them again.
+pub struct Scratch {
+    pub hash: ZopfliHash,
+    /* Cost to reach each position of the block, see get_best_lengths. */
+    pub costs: Vec<f32>,
+    /* Not used by lz77_optimal itself, which gets the cache with the block
+    state, but kept here by its callers between blocks. */
+    pub cache: Option<ZopfliLongestMatchCache>,
+    /* The cost model the first iteration of lz77_optimal_block starts from,
+    instead of the statistics of a greedy parse. */
+    pub seed: Option<SymbolStats>,
+    /* The statistics lz77_optimal found its best parse with the last time. */
+    pub stats: Option<SymbolStats>,
+}
+
+impl Scratch {
+    pub fn new() -> Scratch {
+        Scratch {
+            hash: ZopfliHash::new(),
+            costs: vec![],
+            cache: None,
+            seed: None,
+            stats: None,
+        }
+    }
+}
+
+/// The scratch buffers of a compression and the callback its progress is
+/// reported to, which are handed down together to the optimal parse.
+pub struct Workspace<'a> {
+    pub scratch: &'a mut Scratch,
+    pub progress: &'a mut dyn FnMut(ProgressEvent),
 }
 
 /// Calculates lit/len and dist pairs for given data.
 /// If `instart` is larger than 0, it uses values before `instart` as starting
-/// dictionary.
-pub fn lz77_optimal<C>(s: &mut ZopfliBlockState<C>, in_data: &[u8], instart: usize, inend: usize, numiterations: i32) -> Lz77Store
+/// dictionary. The hash and costs of the scratch buffers of `work` are used
+/// rather than allocating new ones, and improvements are reported to its
+/// progress callback. The first iteration uses `initial_stats` as its cost
+/// model if given, such as the statistics of a similar input compressed before,
+/// and otherwise the statistics of a greedy parse. The statistics the best parse
+/// was found with are kept in the scratch buffers.
+pub fn lz77_optimal<C>(s: &mut ZopfliBlockState<C>, in_data: &[u8], instart: usize, inend: usize, numiterations: i32, initial_stats: Option<SymbolStats>, work: &mut Workspace) -> Lz77Store
     where C: Cache,
 {
     /* Dist to get to here with smallest cost. */
     let mut currentstore = Lz77Store::new();
     let mut outputstore = currentstore.clone();
 
-    /* Initial run. */
-    currentstore.greedy(s, in_data, instart, inend);
-    let mut stats = SymbolStats::default();
-    stats.get_statistics(&currentstore);
-
-    let mut h = ZopfliHash::new();
-    let mut costs = Vec::with_capacity(inend - instart + 1);
+    let mut stats = match initial_stats {
+        Some(mut stats) => {
+            stats.calculate_entropy();
+            stats
+        },
+        None => {
+            /* Initial run. */
+            currentstore.greedy_with_hash(s, &mut work.scratch.hash, in_data, instart, inend);
+            let mut stats = SymbolStats::default();
+            stats.get_statistics(&currentstore);
+            stats
+        },
+    };
 
     let mut beststats = SymbolStats::default();
 
@@ -435,6 +483,8 @@ pub fn lz77_optimal<C>(s: &mut ZopfliBlockState<C>, in_data: &[u8], instart: usi
     /* Try randomizing the costs a bit once the size stabilizes. */
     let mut ran_state = RanState::new();
     let mut lastrandomstep = -1;
+    /* Iterations in a row that didn't improve on bestcost. */
+    let mut stagnant = 0;
 
     /* Do regular deflate, then loop multiple shortest path runs, each time using
     the statistics of the previous run. */
@@ -442,7 +492,7 @@ pub fn lz77_optimal<C>(s: &mut ZopfliBlockState<C>, in_data: &[u8], instart: usi
     run. */
     for i in 0..numiterations {
         currentstore.reset();
-        lz77_optimal_run(s, in_data, instart, inend, |a, b| get_cost_stat(a, b, &stats), &mut currentstore, &mut h, &mut costs);
+        lz77_optimal_run(s, in_data, instart, inend, &stats, &mut currentstore, work.scratch);
         let cost = calculate_block_size(&currentstore, 0, currentstore.size(), BlockType::Dynamic);
 
         if s.options.verbose_more || (s.options.verbose && cost < bestcost) {
@@ -453,6 +503,16 @@ pub fn lz77_optimal<C>(s: &mut ZopfliBlockState<C>, in_data: &[u8], instart: usi
             outputstore = currentstore.clone();
             beststats = stats;
             bestcost = cost;
+            stagnant = 0;
+            (work.progress)(ProgressEvent::Improved { start: instart, end: inend, iteration: i, bits: cost });
+        } else {
+            stagnant += 1;
+            if s.options.max_stagnant_iterations > 0 && stagnant >= s.options.max_stagnant_iterations {
+                if s.options.verbose_more {
+                    println!("Stopping after {} iterations without improvement", stagnant);
+                }
+                break;
+            }
         }
         let laststats = stats;
         stats.clear_freqs();
@@ -461,10 +521,10 @@ pub fn lz77_optimal<C>(s: &mut ZopfliBlockState<C>, in_data: &[u8], instart: usi
             /* This makes it converge slower but better. Do it only once the
             randomness kicks in so that if the user does few iterations, it gives a
             better result sooner. */
-            stats = add_weighed_stat_freqs(&stats, 1.0, &laststats, 0.5);
+            stats.add_weighted_freqs(&laststats, 1.0, 0.5);
             stats.calculate_entropy();
         }
-        if i > 5 && (cost - lastcost).abs() < f64::EPSILON {
+        if i > 5 && (cost - lastcost).abs() < f64::EPSILON && !s.options.deterministic {
             stats = beststats;
             stats.randomize_stat_freqs(&mut ran_state);
             stats.calculate_entropy();
@@ -472,5 +532,253 @@ pub fn lz77_optimal<C>(s: &mut ZopfliBlockState<C>, in_data: &[u8], instart: usi
         }
         lastcost = cost;
     }
+    if bestcost < f64::MAX {
+        work.scratch.stats = Some(beststats);
+    }
     outputstore
 }
+
+#[cfg(test)]
+mod test {
+    use util::pseudo_random_bytes;
+    use super::*;
+    use Options;
+
+    #[test]
+    fn ran_state_is_reproducible() {
+        let sequence = |mut state: RanState| (0..16).map(|_| state.random_marsaglia()).collect::<Vec<_>>();
+        assert_eq!(sequence(RanState::new()), sequence(RanState::new()));
+        assert_eq!(sequence(RanState::new()), sequence(RanState::from_seed(1, 2)));
+        assert_ne!(sequence(RanState::new()), sequence(RanState::from_seed(3, 4)));
+    }
+
+    #[test]
+    fn randomize_stat_freqs_is_reproducible() {
+        let mut stats = SymbolStats::default();
+        for (i, freq) in stats.litlens.iter_mut().enumerate() {
+            *freq = 100 + i % 7;
+        }
+        for (i, freq) in stats.dists.iter_mut().enumerate() {
+            *freq = 50 + i % 5;
+        }
+        let randomized = |seed| {
+            let mut randomized = stats;
+            randomized.randomize_stat_freqs(&mut RanState::from_seed(seed, 2));
+            (randomized.litlens.to_vec(), randomized.dists.to_vec())
+        };
+
+        assert_eq!(randomized(1), randomized(1));
+        assert_ne!(randomized(1), randomized(5));
+        assert_ne!(randomized(1), (stats.litlens.to_vec(), stats.dists.to_vec()));
+
+        // Frequencies are only copied between symbols, so with similar
+        // frequencies everywhere the totals barely move.
+        let (litlens, dists) = randomized(1);
+        let total = |freqs: &[usize]| freqs.iter().sum::<usize>() as f64;
+        let ll_ratio = total(&litlens) / total(&stats.litlens);
+        let d_ratio = total(&dists) / total(&stats.dists);
+        assert!(ll_ratio > 0.95 && ll_ratio < 1.05, "{}", ll_ratio);
+        assert!(d_ratio > 0.9 && d_ratio < 1.1, "{}", d_ratio);
+        assert_eq!(litlens[256], 1);
+    }
+
+    #[test]
+    fn weighted_freqs_are_per_symbol_weighted_sums() {
+        let mut stats1 = SymbolStats::default();
+        let mut stats2 = SymbolStats::default();
+        for i in 0..ZOPFLI_NUM_LL {
+            stats1.litlens[i] = i;
+            stats2.litlens[i] = 4 * i + 3;
+        }
+        for i in 0..ZOPFLI_NUM_D {
+            stats1.dists[i] = 10 * i;
+            stats2.dists[i] = 7;
+        }
+
+        let mut blended = stats1;
+        blended.add_weighted_freqs(&stats2, 1.0, 0.5);
+        for i in 0..ZOPFLI_NUM_LL {
+            let expected = if i == 256 { 1 } else { 3 * i + 1 };
+            assert_eq!(blended.litlens[i], expected, "lit/len symbol {}", i);
+        }
+        for i in 0..ZOPFLI_NUM_D {
+            assert_eq!(blended.dists[i], 10 * i + 3, "dist symbol {}", i);
+        }
+    }
+
+    fn sample_inputs() -> Vec<Vec<u8>> {
+        let text = b"It was the best of times, it was the worst of times, it was the age of wisdom, \
+                     it was the age of foolishness, it was the epoch of belief, it was the epoch of \
+                     incredulity, it was the season of Light, it was the season of Darkness".to_vec();
+        let noisy = pseudo_random_bytes(3000, 1).iter().enumerate().map(|(i, &random)| {
+            if i % 7 == 0 { random } else { b"zopfli"[i % 6] }
+        }).collect();
+        vec![text, noisy, vec![b'x'; 2000]]
+    }
+
+    #[test]
+    fn optimal_is_not_larger_than_greedy() {
+        let options = Options::default();
+        for data in sample_inputs() {
+            let mut greedy = Lz77Store::new();
+            {
+                let mut s = ZopfliBlockState::new(&options, 0, data.len());
+                greedy.greedy(&mut s, &data, 0, data.len());
+            }
+            let mut s = ZopfliBlockState::new(&options, 0, data.len());
+            let optimal = lz77_optimal(&mut s, &data, 0, data.len(), 5, None, &mut Workspace { scratch: &mut Scratch::new(), progress: &mut |_| {} });
+
+            let greedy_cost = calculate_block_size(&greedy, 0, greedy.size(), BlockType::Dynamic);
+            let optimal_cost = calculate_block_size(&optimal, 0, optimal.size(), BlockType::Dynamic);
+            assert!(optimal_cost <= greedy_cost, "{} > {}", optimal_cost, greedy_cost);
+            assert_eq!(optimal.get_byte_range(0, optimal.size()), data.len());
+        }
+    }
+
+    #[test]
+    fn seeded_stats_reach_the_same_size_sooner() {
+        let options = Options::default();
+        /* Noisy enough that the unseeded parse takes a few iterations. */
+        let data = &sample_inputs()[1];
+        /* The iterations that improved the size, and the final statistics. */
+        let run = |initial_stats: Option<SymbolStats>| {
+            let mut s = ZopfliBlockState::new(&options, 0, data.len());
+            let mut scratch = Scratch::new();
+            let mut improved = vec![];
+            lz77_optimal(&mut s, data, 0, data.len(), 10, initial_stats, &mut Workspace {
+                scratch: &mut scratch,
+                progress: &mut |event| {
+                    if let ProgressEvent::Improved { iteration, bits, .. } = event {
+                        improved.push((iteration, bits));
+                    }
+                },
+            });
+            (improved, scratch.stats.unwrap())
+        };
+
+        let (unseeded, stats) = run(None);
+        let (unseeded_iterations, size) = *unseeded.last().unwrap();
+        let (seeded, _) = run(Some(stats));
+        let seeded_iterations = seeded.iter().find(|&&(_, bits)| bits <= size).expect("seeded run reaches the size").0;
+        assert!(seeded_iterations < unseeded_iterations, "seeded after {} iterations, unseeded after {}", seeded_iterations, unseeded_iterations);
+    }
+
+    #[test]
+    fn optimal_fixed_is_not_larger_than_greedy() {
+        let options = Options::default();
+        for data in sample_inputs() {
+            let mut greedy = Lz77Store::new();
+            {
+                let mut s = ZopfliBlockState::new(&options, 0, data.len());
+                greedy.greedy(&mut s, &data, 0, data.len());
+            }
+            let mut fixed = Lz77Store::new();
+            let mut s = ZopfliBlockState::new(&options, 0, data.len());
+            lz77_optimal_fixed(&mut s, &data, 0, data.len(), &mut fixed);
+
+            assert_eq!(fixed.verify(&data), Ok(()));
+            assert_eq!(fixed.get_byte_range(0, fixed.size()), data.len());
+            let greedy_cost = calculate_block_size(&greedy, 0, greedy.size(), BlockType::Fixed);
+            let fixed_cost = calculate_block_size(&fixed, 0, fixed.size(), BlockType::Fixed);
+            assert!(fixed_cost <= greedy_cost, "{} > {}", fixed_cost, greedy_cost);
+        }
+    }
+
+    #[test]
+    fn max_match_limits_match_lengths() {
+        let options = ::OptionsBuilder::new().max_match(10).build();
+        for data in sample_inputs() {
+            let mut greedy = Lz77Store::new();
+            {
+                let mut s = ZopfliBlockState::new(&options, 0, data.len());
+                greedy.greedy(&mut s, &data, 0, data.len());
+            }
+            let mut s = ZopfliBlockState::new(&options, 0, data.len());
+            let optimal = lz77_optimal(&mut s, &data, 0, data.len(), 2, None, &mut Workspace { scratch: &mut Scratch::new(), progress: &mut |_| {} });
+
+            for store in &[greedy, optimal] {
+                assert_eq!(store.verify(&data), Ok(()));
+                let lengths: Vec<u16> = store.litlens.iter().filter_map(|&litlen| match litlen {
+                    LitLen::LengthDist(length, _) => Some(length),
+                    LitLen::Literal(_) => None,
+                }).collect();
+                assert!(!lengths.is_empty());
+                assert!(lengths.iter().all(|&length| length <= 10), "{:?}", lengths);
+            }
+        }
+    }
+
+    #[test]
+    fn max_distance_limits_match_distances() {
+        let options = ::OptionsBuilder::new().max_distance(50).build();
+        for data in sample_inputs() {
+            let mut greedy = Lz77Store::new();
+            {
+                let mut s = ZopfliBlockState::new(&options, 0, data.len());
+                greedy.greedy(&mut s, &data, 0, data.len());
+            }
+            let mut s = ZopfliBlockState::new(&options, 0, data.len());
+            let optimal = lz77_optimal(&mut s, &data, 0, data.len(), 2, None, &mut Workspace { scratch: &mut Scratch::new(), progress: &mut |_| {} });
+
+            for store in &[greedy, optimal] {
+                assert_eq!(store.get_byte_range(0, store.size()), data.len());
+                let dists: Vec<u16> = store.litlens.iter().filter_map(|&litlen| match litlen {
+                    LitLen::LengthDist(_, dist) => Some(dist),
+                    LitLen::Literal(_) => None,
+                }).collect();
+                assert!(!dists.is_empty());
+                assert!(dists.iter().all(|&dist| dist <= 50), "{:?}", dists);
+            }
+        }
+    }
+
+    /// Total cost of the store according to the given cost model.
+    fn store_cost<M>(store: &Lz77Store, costmodel: &M) -> f64
+        where M: CostModel + ?Sized
+    {
+        store.litlens.iter().map(|&litlen| match litlen {
+            LitLen::Literal(lit) => costmodel.cost(lit as u32, 0),
+            LitLen::LengthDist(len, dist) => costmodel.cost(len as u32, dist as u32),
+        }).sum()
+    }
+
+    #[test]
+    fn statistics_model_is_cheaper_for_its_own_store() {
+        let options = Options::default();
+        for data in sample_inputs() {
+            let mut store = Lz77Store::new();
+            let mut s = ZopfliBlockState::new(&options, 0, data.len());
+            store.greedy(&mut s, &data, 0, data.len());
+
+            let mut stats = SymbolStats::default();
+            stats.get_statistics(&store);
+
+            let stat_cost = store_cost(&store, &stats);
+            let fixed_cost = store_cost(&store, &FixedCostModel);
+            assert!(stat_cost < fixed_cost, "{} >= {}", stat_cost, fixed_cost);
+        }
+    }
+
+    #[test]
+    fn cost_models_as_trait_objects() {
+        let mut stats = SymbolStats::default();
+        stats.litlens[b'a' as usize] = 2;
+        stats.litlens[get_length_symbol(10) as usize] = 1;
+        stats.dists[get_dist_symbol(1) as usize] = 1;
+        stats.litlens[256] = 1;
+        stats.calculate_entropy();
+
+        let models: [&dyn CostModel; 2] = [&FixedCostModel, &stats];
+
+        // Fixed tree: 8 bit literal, 7 bit length symbol plus 5 bit dist symbol.
+        assert_eq!(models[0].cost(b'a' as u32, 0), 8.0);
+        assert_eq!(models[0].cost(10, 1), 12.0);
+        assert_eq!(get_cost_model_min_cost(models[0]), 12.0);
+
+        // 'a' makes up half of the 4 lit/len symbols, the length symbol a
+        // quarter, and the only dist symbol costs nothing.
+        assert!((models[1].cost(b'a' as u32, 0) - 1.0).abs() < 1e-9);
+        assert!((models[1].cost(10, 1) - 2.0).abs() < 1e-9);
+        assert_eq!(models[1].cost(10, 1), get_cost_stat(10, 1, &stats));
+    }
+}
@@ -45,21 +45,7 @@ pub fn get_dist_extra_bits(dist: i32) -> i32 {
     if dist < 5 {
         0
     } else {
-        match dist {
-            5...8 => 1,
-            9...16 => 2,
-            17...32 => 3,
-            33...64 => 4,
-            65...128 => 5,
-            129...256 => 6,
-            257...512 => 7,
-            513...1024 => 8,
-            1025...2048 => 9,
-            2049...4096 => 10,
-            4097...8192 => 11,
-            8193...16384 => 12,
-            _ => 13,
-        }
+        dist_log2(dist) - 1
     }
 }
 
@@ -68,56 +54,28 @@ pub fn get_dist_extra_bits_value(dist: i32) -> i32 {
     if dist < 5 {
         0
     } else {
-        match dist {
-            5...8 => (dist - 5) & 1,
-            9...16 => (dist - 9) & 3,
-            17...32 => (dist - 17) & 7,
-            33...64 => (dist - 33) & 15,
-            65...128 => (dist - 65) & 31,
-            129...256 => (dist - 129) & 63,
-            257...512 => (dist - 257) & 127,
-            513...1024 => (dist - 513) & 255,
-            1025...2048 => (dist - 1025) & 511,
-            2049...4096 => (dist - 2049) & 1023,
-            4097...8192 => (dist - 4097) & 2047,
-            8193...16384 => (dist - 8193) & 4095,
-            _ => (dist - 16385) & 8191,
-        }
+        let l = dist_log2(dist);
+        (dist - (1 + (1 << l))) & ((1 << (l - 1)) - 1)
     }
 }
 
+/// Gets the symbol for the given dist, cfr. the DEFLATE spec.
 pub fn get_dist_symbol(dist: i32) -> i32 {
-    match dist {
-        0...4 => dist - 1,
-        5...6 => 4,
-        7...8 => 5,
-        9...12 => 6,
-        13...16 => 7,
-        17...24 => 8,
-        25...32 => 9,
-        33...48 => 10,
-        49...64 => 11,
-        65...96 => 12,
-        97...128 => 13,
-        129...192 => 14,
-        193...256 => 15,
-        257...384 => 16,
-        385...512 => 17,
-        513...768 => 18,
-        769...1024 => 19,
-        1025...1536 => 20,
-        1537...2048 => 21,
-        2049...3072 => 22,
-        3073...4096 => 23,
-        4097...6144 => 24,
-        6145...8192 => 25,
-        8193...12288 => 26,
-        12289...16384 => 27,
-        16385...24576 => 28,
-        _ => 29,
+    if dist < 5 {
+        dist - 1
+    } else {
+        let l = dist_log2(dist);
+        let r = ((dist - 1) >> (l - 1)) & 1;
+        l * 2 + r
     }
 }
 
+/// Index of the highest set bit of dist - 1. Every distance symbol above 3
+/// covers half of such a power of two range.
+fn dist_log2(dist: i32) -> i32 {
+    31 - ((dist - 1) as u32).leading_zeros() as i32
+}
+
 const LENGTH_EXTRA_BITS: [i32; 259] = [
     0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1,
     2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
@@ -181,3 +139,64 @@ const DIST_SYMBOL_EXTRA_BITS_TABLE: [i32; 30] = [
 pub fn get_dist_symbol_extra_bits(s: i32) -> i32 {
     DIST_SYMBOL_EXTRA_BITS_TABLE[s as usize]
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Base values and extra bits of the length and distance codes, as listed
+    // in section 3.2.5 of RFC 1951.
+    const LENGTH_BASE: [i32; 29] = [
+        3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59,
+        67, 83, 99, 115, 131, 163, 195, 227, 258
+    ];
+    const LENGTH_EXTRA: [i32; 29] = [
+        0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4,
+        5, 5, 5, 5, 0
+    ];
+    const DIST_BASE: [i32; 30] = [
+        1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513,
+        769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577
+    ];
+    const DIST_EXTRA: [i32; 30] = [
+        0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10,
+        11, 11, 12, 12, 13, 13
+    ];
+
+    #[test]
+    fn every_length_matches_spec() {
+        let mut covered = 0;
+        for (i, (&base, &extra)) in LENGTH_BASE.iter().zip(LENGTH_EXTRA.iter()).enumerate() {
+            let symbol = 257 + i as i32;
+            assert_eq!(get_length_symbol_extra_bits(symbol), extra);
+            for value in 0..1 << extra {
+                let length = base + value;
+                // Code 284 could express 258, but 258 has its own code 285.
+                if length == 258 && symbol != 285 {
+                    continue;
+                }
+                assert_eq!(get_length_symbol(length as usize), symbol, "length {}", length);
+                assert_eq!(get_length_extra_bits(length as usize), extra, "length {}", length);
+                assert_eq!(get_length_extra_bits_value(length), value, "length {}", length);
+                covered += 1;
+            }
+        }
+        assert_eq!(covered, 258 - 3 + 1);
+    }
+
+    #[test]
+    fn every_dist_matches_spec() {
+        let mut covered = 0;
+        for (symbol, (&base, &extra)) in DIST_BASE.iter().zip(DIST_EXTRA.iter()).enumerate() {
+            assert_eq!(get_dist_symbol_extra_bits(symbol as i32), extra);
+            for value in 0..1 << extra {
+                let dist = base + value;
+                assert_eq!(get_dist_symbol(dist), symbol as i32, "dist {}", dist);
+                assert_eq!(get_dist_extra_bits(dist), extra, "dist {}", dist);
+                assert_eq!(get_dist_extra_bits_value(dist), value, "dist {}", dist);
+                covered += 1;
+            }
+        }
+        assert_eq!(covered, 32768);
+    }
+}
@@ -1,3 +1,14 @@
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::error::Error;
+#[cfg(feature = "std")]
+use std::f64;
+use std::fmt;
+
+#[cfg(feature = "std")]
+const K_INV_LOG2: f64 = f64::consts::LOG2_E;  // 1.0 / log(2.0)
+
 /// Converts a series of Huffman tree bitlengths, to the bit values of the symbols.
 pub fn lengths_to_symbols(lengths: &[u32], max_bits: u32) -> Vec<u32> {
     let mut bl_count = vec![0; (max_bits + 1) as usize];
@@ -30,3 +41,163 @@ pub fn lengths_to_symbols(lengths: &[u32], max_bits: u32) -> Vec<u32> {
     }
     symbols
 }
+
+/// The error of `validate_code_lengths`: why the lengths can't be the code
+/// lengths of a Huffman tree that deflate decoders accept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KraftError {
+    /// The code of `symbol` is longer than `max_bits`.
+    TooLong { symbol: usize, length: u32 },
+    /// There are more codes of some lengths than there are bit patterns.
+    Oversubscribed,
+    /// Some bit patterns are no code, which decoders only accept if there is
+    /// a single code, of one bit.
+    Incomplete,
+}
+
+impl fmt::Display for KraftError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KraftError::TooLong { symbol, length } =>
+                write!(f, "the code of symbol {} is {} bits long, more than allowed", symbol, length),
+            KraftError::Oversubscribed => write!(f, "the code lengths are oversubscribed"),
+            KraftError::Incomplete => write!(f, "the code lengths are incomplete"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for KraftError {}
+
+/// Checks that the code lengths, 0 for unused symbols, describe a complete
+/// prefix code with codes of at most `max_bits` bits, that is that the sum of
+/// `2^(max_bits - length)` over the used symbols is exactly `2^max_bits`. As
+/// deflate allows, no codes at all or a single one-bit code also pass.
+pub fn validate_code_lengths(lengths: &[u32], max_bits: u32) -> Result<(), KraftError> {
+    assert!(max_bits <= 32, "max_bits must be at most 32");
+
+    let mut sum = 0u64;
+    let mut used = 0;
+    for (symbol, &length) in lengths.iter().enumerate() {
+        if length > max_bits {
+            return Err(KraftError::TooLong { symbol, length });
+        }
+        if length != 0 {
+            sum += 1 << (max_bits - length);
+            used += 1;
+        }
+    }
+
+    let full = 1u64 << max_bits;
+    if sum > full {
+        Err(KraftError::Oversubscribed)
+    } else if sum < full && used > 0 && !(used == 1 && sum == full / 2) {
+        Err(KraftError::Incomplete)
+    } else {
+        Ok(())
+    }
+}
+
+/// Calculates the entropy of each symbol, based on the counts of each symbol. The
+/// result is similar to the result of length_limited_code_lengths, but with the
+/// actual theoritical bit lengths according to the entropy. Since the resulting
+/// values are fractional, they cannot be used to encode the tree specified by
+/// DEFLATE.
+#[cfg(feature = "std")]
+pub fn calculate_entropy(count: &[usize]) -> Vec<f64> {
+    let n = count.len();
+
+    let sum: usize = count.iter().sum();
+
+    let log2sum = (if sum == 0 { n } else { sum } as f64).ln() * K_INV_LOG2;
+
+    count.iter().map(|&c| {
+        // When the count of the symbol is 0, but its cost is requested anyway, it
+        // means the symbol will appear at least once anyway, so give it the cost as if
+        // its count is 1.
+        let bitlength = if c == 0 {
+            log2sum
+        } else {
+            log2sum - (c as f64).ln() * K_INV_LOG2
+        };
+
+        // Depending on compiler and architecture, the above subtraction of two
+        // floating point numbers may give a negative result very close to zero
+        // instead of zero (e.g. -5.973954e-17 with gcc 4.1.2 on Ubuntu 11.4). Clamp
+        // it to zero. These floating point imprecisions do not affect the cost model
+        // significantly so this is ok.
+        if bitlength < 0.0 && bitlength > -1E-5 {
+            return 0.0;
+        }
+        debug_assert!(bitlength >= 0.0);
+        bitlength
+    }).collect()
+}
+
+/// The total cost in bits of encoding the histogram with its entropy bit lengths.
+#[cfg(feature = "std")]
+pub fn histogram_cost(count: &[usize]) -> f64 {
+    count.iter()
+        .zip(calculate_entropy(count))
+        .map(|(&c, bitlength)| c as f64 * bitlength)
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use katajainen::length_limited_code_lengths;
+
+    #[test]
+    fn symbols_of_length_limited_code() {
+        // Also runs without std, see the README.
+        let lengths = length_limited_code_lengths(&[1, 1, 5, 7, 10, 14], 4).unwrap();
+        assert_eq!(lengths, [4, 4, 3, 2, 2, 2]);
+        let symbols = lengths_to_symbols(&lengths, 4);
+        assert_eq!(symbols, [0b1110, 0b1111, 0b110, 0b00, 0b01, 0b10]);
+    }
+
+    #[test]
+    fn code_lengths_of_complete_trees_are_valid() {
+        let lengths = length_limited_code_lengths(&[1, 1, 5, 7, 10, 14, 0], 4).unwrap();
+        assert_eq!(validate_code_lengths(&lengths, 4), Ok(()));
+        assert_eq!(validate_code_lengths(&lengths, 15), Ok(()));
+        // No codes, and a single code of one bit.
+        assert_eq!(validate_code_lengths(&[0, 0, 0], 15), Ok(()));
+        assert_eq!(validate_code_lengths(&[0, 1, 0], 15), Ok(()));
+    }
+
+    #[test]
+    fn code_lengths_of_broken_trees_are_invalid() {
+        assert_eq!(validate_code_lengths(&[1, 1, 2], 15), Err(KraftError::Oversubscribed));
+        assert_eq!(validate_code_lengths(&[1, 2, 3], 15), Err(KraftError::Incomplete));
+        assert_eq!(validate_code_lengths(&[0, 2], 15), Err(KraftError::Incomplete));
+        assert_eq!(validate_code_lengths(&[1, 2, 8, 8], 7), Err(KraftError::TooLong { symbol: 2, length: 8 }));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn entropy_of_small_distribution() {
+        // Probabilities 1/2, 1/4, 1/8 and 1/8.
+        let count = [4, 2, 1, 1, 0];
+        let bitlengths = calculate_entropy(&count);
+        let expected = [1.0, 2.0, 3.0, 3.0, 3.0];
+        for (&b, &e) in bitlengths.iter().zip(expected.iter()) {
+            assert!((b - e).abs() < 1e-9, "{:?}", bitlengths);
+        }
+        assert!((histogram_cost(&count) - 14.0).abs() < 1e-9);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn entropy_of_empty_and_single_symbol_histograms() {
+        // Without any counts every symbol costs log2(n).
+        let bitlengths = calculate_entropy(&[0; 8]);
+        assert!(bitlengths.iter().all(|&b| (b - 3.0).abs() < 1e-9));
+        assert_eq!(histogram_cost(&[0; 8]), 0.0);
+
+        // A symbol that is certain costs nothing, never a negative amount.
+        assert_eq!(calculate_entropy(&[0, 7, 0])[1], 0.0);
+        assert_eq!(histogram_cost(&[0, 7, 0]), 0.0);
+    }
+}
@@ -1,3 +1,9 @@
+// Without std only the options use these, for their defaults.
+#![cfg_attr(not(feature = "std"), allow(dead_code))]
+
+#[cfg(feature = "std")]
+use std::io::{self, Write};
+
 /// Number of distinct literal/length symbols in DEFLATE
 pub const ZOPFLI_NUM_LL: usize = 288;
 /// Number of distinct distance symbols in DEFLATE
@@ -38,3 +44,47 @@ pub const ZOPFLI_MAX_CHAIN_HITS: usize = 8192;
 /// be executed independently on each huge block.
 /// Dividing into huge blocks hurts compression, but not much relative to the size.
 pub const ZOPFLI_MASTER_BLOCK_SIZE: usize = 1000000;
+
+/// Passes writes through to `inner`, counting the bytes written.
+#[cfg(feature = "std")]
+pub struct CountingWriter<W> {
+    pub inner: W,
+    pub count: u64,
+}
+
+#[cfg(feature = "std")]
+impl<W> CountingWriter<W> {
+    pub fn new(inner: W) -> CountingWriter<W> {
+        CountingWriter {
+            inner,
+            count: 0,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Deterministic, incompressible-looking bytes for tests (xorshift32). `seed`
+/// must not be 0.
+#[cfg(all(test, feature = "std"))]
+pub fn pseudo_random_bytes(len: usize, seed: u32) -> Vec<u8> {
+    debug_assert!(seed != 0);
+    let mut state = seed;
+    (0..len).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        (state >> 24) as u8
+    }).collect()
+}
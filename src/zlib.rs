@@ -1,24 +1,128 @@
 use std::io::{self, Write};
-use adler32::adler32;
+use adler32::RollingAdler32;
 use byteorder::{BigEndian, WriteBytesExt};
 
-use deflate::{deflate, BlockType};
+use deflate::{deflate, deflate_from, BlockType};
+use squeeze::Workspace;
+use util::ZOPFLI_WINDOW_SIZE;
 use Options;
 
-pub fn zlib_compress<W>(options: &Options, in_data: &[u8], mut out: W) -> io::Result<()>
-    where W: Write
-{
+/// Calculates the Adler-32 checksum of the data, as used in the zlib trailer.
+pub fn adler32(data: &[u8]) -> u32 {
+    RollingAdler32::from_buffer(data).hash()
+}
+
+/// The two-byte CMF/FLG header that starts a zlib stream.
+pub fn zlib_header() -> u16 {
+    zlib_header_with_fdict(false)
+}
+
+/// Like `zlib_header`, with the FDICT flag set if `fdict` is true, for
+/// streams that need a preset dictionary.
+fn zlib_header_with_fdict(fdict: bool) -> u16 {
     let cmf = 120;  /* CM 8, CINFO 7. See zlib spec.*/
     let flevel = 3;
-    let fdict = 0;
-    let mut cmfflg = 256 * cmf + fdict * 32 + flevel * 64;
+    let fdict = fdict as u16;
+    let cmfflg = 256 * cmf + fdict * 32 + flevel * 64;
     let fcheck = 31 - cmfflg % 31;
-    cmfflg += fcheck;
+    cmfflg + fcheck
+}
+
+pub fn zlib_compress<W>(options: &Options, in_data: &[u8], mut out: W, work: &mut Workspace) -> io::Result<()>
+    where W: Write
+{
+    try!(out.by_ref().write_u16::<BigEndian>(zlib_header()));
+
+    try!(deflate(options, BlockType::Dynamic, in_data, out.by_ref(), work));
+
+    out.write_u32::<BigEndian>(adler32(in_data))
+}
+
+/// Like `zlib_compress`, but for a decoder that is given `dictionary` as the
+/// preset dictionary: the header has FDICT set and is followed by the Adler-32
+/// of the dictionary, and matches may refer back into its last
+/// `ZOPFLI_WINDOW_SIZE` bytes.
+pub fn zlib_compress_with_dictionary<W>(options: &Options, dictionary: &[u8], in_data: &[u8], mut out: W, work: &mut Workspace) -> io::Result<()>
+    where W: Write
+{
+    try!(out.by_ref().write_u16::<BigEndian>(zlib_header_with_fdict(true)));
+    try!(out.by_ref().write_u32::<BigEndian>(adler32(dictionary)));
+
+    let window = &dictionary[dictionary.len().saturating_sub(ZOPFLI_WINDOW_SIZE)..];
+    let mut buffer = Vec::with_capacity(window.len() + in_data.len());
+    buffer.extend_from_slice(window);
+    buffer.extend_from_slice(in_data);
+    try!(deflate_from(options, BlockType::Dynamic, &buffer, window.len(), out.by_ref(), work));
+
+    out.write_u32::<BigEndian>(adler32(in_data))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use flate2::{Decompress, FlushDecompress, Status};
+    use flate2::read::ZlibDecoder;
+
+    use squeeze::Scratch;
+    use super::*;
+
+    #[test]
+    fn adler32_known_values() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn zlib_decoder_accepts_output() {
+        let data = b"zlib zlib zlib, adler adler adler";
+        let mut compressed = vec![];
+        zlib_compress(&Options::default(), data, &mut compressed, &mut Workspace { scratch: &mut Scratch::new(), progress: &mut |_| {} }).unwrap();
+
+        assert_eq!((compressed[0] as u16 * 256 + compressed[1] as u16) % 31, 0);
+        assert_eq!(&compressed[compressed.len() - 4..], &[
+            (adler32(data) >> 24) as u8,
+            (adler32(data) >> 16) as u8,
+            (adler32(data) >> 8) as u8,
+            adler32(data) as u8,
+        ]);
+
+        let mut decompressed = vec![];
+        ZlibDecoder::new(&compressed[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, data.to_vec());
+    }
+
+    #[test]
+    fn dictionary_is_referenced_and_announced() {
+        let dictionary = b"{\"name\": \"\", \"email\": \"\", \"roles\": [\"reader\", \"writer\"]}";
+        let data = b"{\"name\": \"Ada\", \"email\": \"ada@example.com\", \"roles\": [\"writer\"]}";
+        let mut compressed = vec![];
+        zlib_compress_with_dictionary(&Options::default(), dictionary, data, &mut compressed, &mut Workspace { scratch: &mut Scratch::new(), progress: &mut |_| {} }).unwrap();
 
-    try!(out.by_ref().write_u16::<BigEndian>(cmfflg));
+        let header = compressed[0] as u16 * 256 + compressed[1] as u16;
+        assert_eq!(header % 31, 0);
+        assert_eq!(header & 0x20, 0x20, "FDICT not set");
+        assert_eq!(&compressed[2..6], &[
+            (adler32(dictionary) >> 24) as u8,
+            (adler32(dictionary) >> 16) as u8,
+            (adler32(dictionary) >> 8) as u8,
+            adler32(dictionary) as u8,
+        ]);
 
-    try!(deflate(options, BlockType::Dynamic, in_data, out.by_ref()));
+        // The inflater asks for the dictionary by its Adler-32 after the header,
+        // and checks the Adler-32 of the data at the end.
+        let mut inflater = Decompress::new(true);
+        let mut decompressed = Vec::with_capacity(2 * data.len());
+        let error = inflater.decompress_vec(&compressed, &mut decompressed, FlushDecompress::Finish).unwrap_err();
+        assert_eq!(error.needs_dictionary(), Some(adler32(dictionary)));
+        inflater.set_dictionary(dictionary).unwrap();
+        let consumed = inflater.total_in() as usize;
+        let status = inflater.decompress_vec(&compressed[consumed..], &mut decompressed, FlushDecompress::Finish).unwrap();
+        assert_eq!(status, Status::StreamEnd);
+        assert_eq!(&decompressed[..], &data[..]);
 
-    let checksum = adler32(io::Cursor::new(&in_data)).expect("Error with adler32");
-    out.write_u32::<BigEndian>(checksum)
+        let mut without_dictionary = vec![];
+        zlib_compress(&Options::default(), data, &mut without_dictionary, &mut Workspace { scratch: &mut Scratch::new(), progress: &mut |_| {} }).unwrap();
+        assert!(compressed.len() - 4 < without_dictionary.len());
+    }
 }
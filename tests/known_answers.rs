@@ -0,0 +1,31 @@
+//! Compares the output with what the reference C zopfli produces for the files
+//! in `test/data`, which is stored in `test/results` (see `test/run.sh`). If a
+//! change alters the output on purpose, regenerate those files with the C
+//! implementation rather than with this one.
+
+#![cfg(feature = "std")]
+
+extern crate zopfli;
+
+use zopfli::{compress_to_vec, Format, Options};
+
+fn check(data: &[u8], expected: &[u8]) {
+    let compressed = compress_to_vec(&Options::default(), &Format::Gzip, data);
+    assert_eq!(compressed.len(), expected.len());
+    assert!(compressed == expected, "output differs from the C implementation");
+}
+
+#[test]
+fn thirty_min_csv() {
+    check(include_bytes!("../test/data/30-min.csv"), include_bytes!("../test/results/30-min.csv.gz"));
+}
+
+#[test]
+fn computer_png() {
+    check(include_bytes!("../test/data/computer.png"), include_bytes!("../test/results/computer.png.gz"));
+}
+
+#[test]
+fn heartbleed_png() {
+    check(include_bytes!("../test/data/heartbleed.png"), include_bytes!("../test/results/heartbleed.png.gz"));
+}
@@ -0,0 +1,84 @@
+//! Checks that everything zopfli produces decompresses back to its input with
+//! an independent decoder.
+
+#![cfg(feature = "std")]
+
+extern crate flate2;
+extern crate zopfli;
+
+use std::io::Read;
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use zopfli::{compress_to_vec, Format, Options, OptionsBuilder};
+
+fn decompress(output_type: Format, compressed: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    match output_type {
+        Format::Gzip => GzDecoder::new(compressed).read_to_end(&mut out),
+        Format::Zlib => ZlibDecoder::new(compressed).read_to_end(&mut out),
+        Format::Deflate => DeflateDecoder::new(compressed).read_to_end(&mut out),
+    }.unwrap_or_else(|e| panic!("{:?} output doesn't decompress: {}", output_type, e));
+    out
+}
+
+fn random_bytes(len: usize, seed: u32) -> Vec<u8> {
+    let mut x = seed;
+    (0..len).map(|_| {
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        x as u8
+    }).collect()
+}
+
+fn inputs() -> Vec<(&'static str, Vec<u8>)> {
+    let text = b"The quick brown fox jumps over the lazy dog. Pack my box with five dozen liquor jugs. ";
+    vec![
+        ("empty", vec![]),
+        ("one byte", vec![42]),
+        ("same byte", vec![0; 2000]),
+        ("random", random_bytes(5000, 7)),
+        ("repetitive text", text.iter().cycle().take(8000).cloned().collect()),
+        ("binary", (0..1500u32).flat_map(|i| {
+            let v = i * i;
+            vec![v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]
+        }).collect()),
+        ("mixed", {
+            let mut data = text.repeat(20);
+            data.extend(random_bytes(2000, 11));
+            data.extend(vec![b'z'; 1000]);
+            data.extend(text.repeat(20));
+            data
+        }),
+    ]
+}
+
+fn check_round_trip(options: &Options) {
+    for (name, data) in inputs() {
+        for &output_type in &[Format::Deflate, Format::Zlib, Format::Gzip] {
+            let compressed = compress_to_vec(options, &output_type, &data);
+            assert!(decompress(output_type, &compressed) == data, "{} as {:?} didn't round trip", name, output_type);
+        }
+    }
+}
+
+#[test]
+fn round_trip_default_options() {
+    // Fewer iterations than the default to keep debug builds fast.
+    check_round_trip(&OptionsBuilder::new().numiterations(5).build());
+}
+
+#[test]
+fn round_trip_without_blocksplitting() {
+    check_round_trip(&OptionsBuilder::new().numiterations(3).blocksplitting(false).build());
+}
+
+#[test]
+fn round_trip_with_many_blocks() {
+    check_round_trip(&OptionsBuilder::new().numiterations(3).blocksplittingmax(0).build());
+}
+
+#[test]
+fn round_trip_with_merged_blocks() {
+    check_round_trip(&OptionsBuilder::new().numiterations(3).blocksplittingmax(0).merge_blocks(true).build());
+}